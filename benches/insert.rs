@@ -0,0 +1,142 @@
+//! Client side cost of inserting a batch with each `sender.clickhouse.format`, against a local
+//! HTTP server that only reads the request: `cargo bench --bench insert`. The `strings` case is
+//! the reference, every value bound as a string with one `?` per cell.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use chrono::NaiveDateTime;
+use clickhouse::Client;
+
+use hyper::{Body, Request, Response, Server};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Map};
+
+use log2click::{LogMessage, SendTrait};
+use log2click::config::Log2ClickConfig;
+use log2click::error::SyncError;
+use log2click::policy::{OnError, Stage};
+use log2click::sink::Clickhouse;
+
+const BATCH_SIZE: usize = 10_000;
+const ROUNDS: usize = 20;
+
+fn batch() -> Vec<LogMessage> {
+    (0..BATCH_SIZE)
+        .map(|offset| {
+            let fields = json!({
+                "date": "2024-03-19 19:01:32.737",
+                "level": "INFO",
+                "thread": "http-nio-8080-exec-7",
+                "class": "com.example.order.OrderController",
+                "message": format!("Order {} created for customer {} in 12 ms", offset, offset % 97),
+            });
+            LogMessage {
+                map: fields.as_object().cloned().or(Some(Map::new())),
+                ..LogMessage::new("logs", 0, offset as i64, "")
+            }
+        })
+        .collect()
+}
+
+fn sink(server: &str, format: &str) -> Result<Clickhouse, SyncError> {
+    let conf = Log2ClickConfig::from_yaml(&format!(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group-id: bench
+    username: bench
+    password: bench
+parser:
+  regex: '(.*) (.*) (.*) (.*) (.*)'
+  mapping: date, level, thread, class, message
+sender:
+  mapping:
+    date: date
+    level: level
+    thread: thread
+    class: class
+    message: message
+  date-format:
+    date: '%Y-%m-%d %H:%M:%S%.3f'
+  clickhouse:
+    server: {}
+    database: default
+    table: logs
+    format: {}
+"#, server, format))?;
+    Clickhouse::create(&conf.pipelines[0].sender, OnError::fail(Stage::Sink))
+}
+
+const COLUMNS: [&str; 5] = ["date", "level", "thread", "class", "message"];
+
+/// Inserts a batch the way values were bound before the column types were known.
+async fn strings(client: &Client, batch: Vec<LogMessage>) -> Result<(), SyncError> {
+    let params = format!("({})", vec!["?"; COLUMNS.len()].join(", "));
+    let sql = format!("INSERT INTO logs ({}) VALUES\n{}", COLUMNS.join(", "), vec![params; batch.len()].join(", "));
+    let mut query = client.query(&sql);
+    for message in &batch {
+        let map = message.map.as_ref().ok_or(SyncError::Option)?;
+        for column in COLUMNS {
+            let value = map.get(column).ok_or(SyncError::Option)?.as_str().unwrap_or("").to_owned();
+            query = match column {
+                "date" => query.bind(NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S%.3f")
+                    .unwrap_or_default()
+                    .format("%Y-%m-%d %H:%M:%S").to_string()),
+                _ => query.bind(value),
+            };
+        }
+    }
+    Ok(query.execute().await?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), SyncError> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let service = make_service_fn(move |_| {
+        let counter = counter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let counter = counter.clone();
+                async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await?;
+                    counter.fetch_add(body.len(), Ordering::Relaxed);
+                    Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+    let address = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    println!("{} rows per batch, {} rounds", BATCH_SIZE, ROUNDS);
+    for format in ["strings", "values", "row-binary"] {
+        let client = Client::default().with_url(&address).with_database("default");
+        let sink = match format {
+            "strings" => None,
+            format => Some(sink(&address, format)?),
+        };
+        let push = |batch| async {
+            match &sink {
+                Some(sink) => sink.push(batch).await,
+                None => strings(&client, batch).await,
+            }
+        };
+        push(batch()).await?;
+        let batches: Vec<Vec<LogMessage>> = (0..ROUNDS).map(|_| batch()).collect();
+        received.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        for batch in batches {
+            push(batch).await?;
+        }
+        let elapsed = start.elapsed() / ROUNDS as u32;
+        let size = received.load(Ordering::Relaxed) / ROUNDS;
+        println!("{:<12} {:>10.2?} per batch, {:>8} KiB sent", format, elapsed, size / 1024);
+    }
+    Ok(())
+}
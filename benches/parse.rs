@@ -0,0 +1,108 @@
+//! Throughput of the Json and Regex filters with each `parser.workers`, over Spring Boot logs of
+//! which a part carries a Java stack trace: `cargo bench --bench parse`.
+
+use std::thread;
+use std::time::Instant;
+
+use serde_json::json;
+
+use log2click::{Filter, LogMessage};
+use log2click::config::Log2ClickConfig;
+use log2click::error::SyncError;
+use log2click::parser::{Json, Regular};
+use log2click::policy::{OnError, Stage};
+
+const BATCH_SIZE: usize = 5_000;
+const ROUNDS: usize = 10;
+
+const STACK_TRACE: &str = "\
+java.lang.IllegalStateException: Order 42 cannot be shipped, it has not been paid
+\tat com.example.order.OrderService.ship(OrderService.java:118)
+\tat com.example.order.OrderService$$FastClassBySpringCGLIB$$7c3b1f.invoke(<generated>)
+\tat org.springframework.cglib.proxy.MethodProxy.invoke(MethodProxy.java:218)
+\tat org.springframework.aop.framework.CglibAopProxy$CglibMethodInvocation.invokeJoinpoint(CglibAopProxy.java:793)
+\tat org.springframework.aop.framework.ReflectiveMethodInvocation.proceed(ReflectiveMethodInvocation.java:163)
+\tat org.springframework.transaction.interceptor.TransactionInterceptor.invoke(TransactionInterceptor.java:119)
+\tat org.springframework.aop.framework.ReflectiveMethodInvocation.proceed(ReflectiveMethodInvocation.java:186)
+\tat org.springframework.aop.framework.CglibAopProxy$DynamicAdvisedInterceptor.intercept(CglibAopProxy.java:723)
+\tat com.example.order.OrderService$$EnhancerBySpringCGLIB$$9d2e4a.ship(<generated>)
+\tat com.example.order.OrderController.ship(OrderController.java:64)
+\tat java.base/jdk.internal.reflect.NativeMethodAccessorImpl.invoke0(Native Method)
+\tat java.base/java.lang.reflect.Method.invoke(Method.java:568)
+\tat org.springframework.web.method.support.InvocableHandlerMethod.doInvoke(InvocableHandlerMethod.java:205)
+\tat org.springframework.web.servlet.DispatcherServlet.doDispatch(DispatcherServlet.java:1072)
+\tat org.springframework.web.servlet.FrameworkServlet.processRequest(FrameworkServlet.java:1006)
+\tat javax.servlet.http.HttpServlet.service(HttpServlet.java:681)
+\tat org.apache.catalina.core.ApplicationFilterChain.internalDoFilter(ApplicationFilterChain.java:227)
+\tat org.apache.catalina.core.StandardWrapperValve.invoke(StandardWrapperValve.java:197)
+\tat org.apache.coyote.http11.Http11Processor.service(Http11Processor.java:391)
+\tat org.apache.tomcat.util.net.NioEndpoint$SocketProcessor.doRun(NioEndpoint.java:1789)
+\tat java.base/java.lang.Thread.run(Thread.java:833)
+Caused by: java.sql.SQLTransientConnectionException: HikariPool-1 - Connection is not available, request timed out after 30000ms.
+\tat com.zaxxer.hikari.pool.HikariPool.createTimeoutException(HikariPool.java:696)
+\tat com.zaxxer.hikari.pool.HikariPool.getConnection(HikariPool.java:181)
+\t... 42 common frames omitted";
+
+/// One record in five is an error with a stack trace, as on the nodes running the services.
+fn batch() -> Vec<LogMessage> {
+    (0..BATCH_SIZE)
+        .map(|offset| {
+            let log = match offset % 5 {
+                0 => format!("2024-03-19 19:01:32.737 ERROR [http-nio-8080-exec-7] com.example.order.OrderController : Request {} failed\n{}", offset, STACK_TRACE),
+                _ => format!("2024-03-19 19:01:32.737 INFO [http-nio-8080-exec-7] com.example.order.OrderController : Order {} created for customer {} in 12 ms", offset, offset % 97),
+            };
+            LogMessage::new("logs", 0, offset as i64, json!({ "log": log, "env": "prod", "service_code": "order" }).to_string())
+        })
+        .collect()
+}
+
+fn filters(workers: usize) -> Result<Vec<Box<dyn Filter>>, SyncError> {
+    let conf = Log2ClickConfig::from_yaml(&format!(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group-id: bench
+    username: bench
+    password: bench
+parser:
+  regex: '(\d{{4}}-\d{{2}}-\d{{2}} \d{{2}}:\d{{2}}:\d{{2}}\.\d+) (\w+) \[([^]]+)\] ([^:]+) : ([\s\S]*)'
+  mapping: date, level, thread, class, message
+  workers: {}
+sender:
+  mapping:
+    date: date
+    message: message
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: default
+    table: logs
+"#, workers))?;
+    let parser = &conf.pipelines[0].parser;
+    Ok(vec![
+        Box::new(Json::create(parser, OnError::fail(Stage::Json))),
+        Box::new(Regular::create(parser, OnError::fail(Stage::Regex))?),
+    ])
+}
+
+#[tokio::main]
+async fn main() -> Result<(), SyncError> {
+    let cores = thread::available_parallelism().map(|it| it.get()).unwrap_or(1);
+    println!("{} records per batch, {} rounds, {} cores", BATCH_SIZE, ROUNDS, cores);
+    let mut workers = 1;
+    while workers <= cores.max(2) {
+        let filters = filters(workers)?;
+        let batches: Vec<Vec<LogMessage>> = (0..ROUNDS).map(|_| batch()).collect();
+        let start = Instant::now();
+        for mut batch in batches {
+            for x in &filters {
+                batch = x.process(batch).await?;
+            }
+        }
+        let elapsed = start.elapsed();
+        let rate = (BATCH_SIZE * ROUNDS) as f64 / elapsed.as_secs_f64();
+        println!("{:>2} workers {:>10.2?} per batch, {:>10.0} records/s", workers, elapsed / ROUNDS as u32, rate);
+        workers *= 2;
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::info;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{LogMessage, SendTrait};
+use crate::error::SyncError;
+
+/// File appended to, rotated once it reaches `max_size` bytes.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, max_files: usize) -> RotatingFile {
+        RotatingFile { path: PathBuf::from(path), max_size, max_files, file: Mutex::new(None) }
+    }
+
+    pub fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// `<path>` becomes `<path>.1`, `<path>.1` becomes `<path>.2` and so on, the oldest is dropped.
+    fn rotate(&self) -> Result<(), SyncError> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(from, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        Ok(())
+    }
+
+    /// Writes `content` at once, the file is only rotated afterwards.
+    pub fn append(&self, content: &str) -> Result<(), SyncError> {
+        let mut file = self.file.lock().map_err(|_| SyncError::Option)?;
+        if file.is_none() {
+            let opened = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            let size = opened.metadata()?.len();
+            *file = Some((opened, size));
+        }
+        if let Some((opened, size)) = file.as_mut() {
+            opened.write_all(content.as_bytes())?;
+            *size += content.len() as u64;
+            if *size >= self.max_size {
+                opened.flush()?;
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// [`append`](Self::append) on the blocking pool, the runtime threads don't wait on the disk.
+    pub async fn write(self: Arc<Self>, content: String) -> Result<(), SyncError> {
+        tokio::task::spawn_blocking(move || self.append(&content))
+            .await
+            .map_err(|e| SyncError::OptionParams(format!("File writer ended abnormally: {}", e)))?
+    }
+}
+
+/// A record as it is archived: the parsed fields, the payload when it has not been parsed.
+#[derive(Serialize)]
+struct Archived<'a> {
+    topic: &'a str,
+    partition: i32,
+    offset: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<&'a str>,
+}
+
+/// Sink appending the records of every batch to an NDJSON file.
+pub struct Archive {
+    file: Arc<RotatingFile>,
+}
+
+impl Archive {
+    pub fn create(path: &str, max_size: u64, max_files: usize) -> Archive {
+        info!("[Archive] Records are written to {}", path);
+        Archive { file: Arc::new(RotatingFile::new(path, max_size, max_files)) }
+    }
+}
+
+#[async_trait]
+impl SendTrait for Archive {
+    async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+        let mut content = String::new();
+        for x in &message {
+            let record = Archived {
+                topic: &x.topic,
+                partition: x.partition,
+                offset: x.offset,
+                fields: x.map.as_ref(),
+                body: if x.map.is_none() { Some(&x.body) } else { None },
+            };
+            content.push_str(&serde_json::to_string(&record)?);
+            content.push('\n');
+        }
+        self.file.clone().write(content).await?;
+        info!("[Archive] {} records have been archived", message.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, max_size: u64, max_files: usize) -> Result<RotatingFile, SyncError> {
+        let dir = std::env::temp_dir().join(format!("log2click-archive-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        Ok(RotatingFile::new(dir.join("records.ndjson").to_str().unwrap(), max_size, max_files))
+    }
+
+    #[test]
+    fn rotate_once_full() -> Result<(), SyncError> {
+        let file = file("rotate", 10, 2)?;
+        file.append("12345\n")?;
+        assert_eq!(fs::read_to_string(&file.path)?, "12345\n");
+        // Written at once even though it goes over the size, then rotated.
+        file.append("67890\n")?;
+        assert!(!file.path.exists());
+        assert_eq!(fs::read_to_string(file.rotated(1))?, "12345\n67890\n");
+        for content in ["a".repeat(10), "b".repeat(10), "c".repeat(10)] {
+            file.append(&content)?;
+        }
+        // The oldest files are dropped.
+        assert_eq!(fs::read_to_string(file.rotated(1))?, "c".repeat(10));
+        assert_eq!(fs::read_to_string(file.rotated(2))?, "b".repeat(10));
+        assert!(!file.rotated(3).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn size_limit_counts_existing_content() -> Result<(), SyncError> {
+        let file = file("size", 10, 0)?;
+        fs::write(&file.path, "12345678")?;
+        file.append("9")?;
+        assert_eq!(fs::read_to_string(&file.path)?, "123456789");
+        // Without rotated files, the full file is dropped.
+        file.append("0")?;
+        assert!(!file.path.exists());
+        assert!(!file.rotated(1).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,1181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use chrono::format::{Item, StrftimeItems};
+use indexmap::IndexMap;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::LogMessage;
+use crate::error::SyncError;
+use crate::schema::ColumnType;
+
+/// Root of the `log2click` configuration.
+#[derive(Debug, Clone)]
+pub struct Log2ClickConfig {
+    /// Run side by side, each one restarted on its own when it fails.
+    pub pipelines: Vec<PipelineConfig>,
+    pub shutdown: ShutdownConfig,
+    /// Server of the metrics and the probes, none without it.
+    pub http: Option<HttpConfig>,
+}
+
+/// The YAML document: a list of `pipelines`, or the keys of a single pipeline at the root.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    pipelines: Vec<PipelineConfig>,
+    receive: Option<ReceiveConfig>,
+    parser: Option<ParserConfig>,
+    sender: Option<SenderConfig>,
+    outputs: Option<Vec<OutputConfig>>,
+    commit: Option<CommitPolicy>,
+    best_effort_timeout: Option<u64>,
+    queue_size: Option<usize>,
+    on_error: Option<OnErrorConfig>,
+    dead_letter: Option<DeadLetterConfig>,
+    #[serde(default)]
+    shutdown: ShutdownConfig,
+    http: Option<HttpConfig>,
+}
+
+/// A Kafka source, its filters and its Clickhouse sink.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Prefix of the log lines of the pipeline.
+    pub name: String,
+    pub receive: ReceiveConfig,
+    pub parser: ParserConfig,
+    pub sender: SenderConfig,
+    /// Sinks every batch is written to as well as `sender`.
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+    /// Sinks that must have written a batch for its offsets to be committed.
+    #[serde(default)]
+    pub commit: CommitPolicy,
+    /// Milliseconds the sinks not required by `commit` are given to write a batch, counted from
+    /// the start of the batch; the ones still writing once the required ones are done skip it.
+    #[serde(default = "default_best_effort_timeout")]
+    pub best_effort_timeout: u64,
+    /// Batches queued between the source, the filters and the sinks, which run concurrently.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub on_error: OnErrorConfig,
+    /// Target of the records rejected with the `dead-letter` policy; they are only logged without one.
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterConfig>,
+    /// YAML path of the pipeline, prefixed to the paths of its problems.
+    #[serde(skip)]
+    path: String,
+}
+
+/// A sink besides `sender`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum OutputConfig {
+    /// Another Clickhouse table, possibly on another server.
+    Clickhouse {
+        #[serde(default)]
+        primary: bool,
+        sender: Box<SenderConfig>,
+    },
+    /// An NDJSON archive of the parsed records, rotated into `<path>.1` .. `<path>.<max-files>`.
+    #[serde(rename_all = "kebab-case")]
+    File {
+        #[serde(default)]
+        primary: bool,
+        path: String,
+        #[serde(default = "default_file_max_size")]
+        max_size: u64,
+        #[serde(default = "default_file_max_files")]
+        max_files: usize,
+    },
+}
+
+impl OutputConfig {
+    pub fn primary(&self) -> bool {
+        match self {
+            OutputConfig::Clickhouse { primary, .. } | OutputConfig::File { primary, .. } => *primary,
+        }
+    }
+}
+
+/// Which sinks must write a batch for it to be committed. A batch is retried on every sink
+/// when it is not, sinks without deduplication may then get it twice. A required Clickhouse
+/// sink retrying a transient failure holds the batch back, the other sinks are waited for
+/// `best-effort-timeout` at most. A pipeline with an `exactly-once` sink must commit `all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommitPolicy {
+    #[default]
+    All,
+    Any,
+    /// `sender` and the outputs marked `primary`, the others are best effort.
+    Primary,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReceiveConfig {
+    pub kafka: KafkaConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// Comma separated list of `host:port` brokers.
+    pub server: String,
+    /// Topics subscribed to, those starting with `^` are regular expressions matching the
+    /// topics to subscribe to, including the ones created later.
+    #[serde(alias = "topic", deserialize_with = "string_list")]
+    pub topics: Vec<String>,
+    #[serde(alias = "group_id")]
+    pub group_id: String,
+    /// `SASL_PLAINTEXT` when a `username` is given, `PLAINTEXT` otherwise.
+    #[serde(default)]
+    pub security_protocol: Option<SecurityProtocol>,
+    #[serde(default)]
+    pub sasl_mechanism: SaslMechanism,
+    /// SASL credentials, required by the `PLAIN` and `SCRAM` mechanisms.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Certificates of the `SSL` and `SASL_SSL` protocols.
+    #[serde(default)]
+    pub ssl: Option<SslConfig>,
+    /// librdkafka properties set as they are, over the ones above, e.g. `sasl.oauthbearer.config`
+    /// or `fetch.max.bytes`.
+    #[serde(default)]
+    pub properties: IndexMap<String, String>,
+    /// Position of the partitions the group has not committed an offset of yet, e.g. when the
+    /// group is new. Committed and stored offsets always prevail.
+    #[serde(default)]
+    pub start_from: StartFrom,
+    /// Maximum number of messages in one batch.
+    #[serde(default = "default_batch_size")]
+    pub size: usize,
+    /// Maximum time in milliseconds to wait for a batch to fill up.
+    #[serde(default = "default_batch_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    /// Value of the `security.protocol` property.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "PLAINTEXT",
+            SecurityProtocol::Ssl => "SSL",
+            SecurityProtocol::SaslPlaintext => "SASL_PLAINTEXT",
+            SecurityProtocol::SaslSsl => "SASL_SSL",
+        }
+    }
+
+    pub fn is_sasl(&self) -> bool {
+        matches!(self, SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl)
+    }
+
+    pub fn is_ssl(&self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum SaslMechanism {
+    #[default]
+    #[serde(rename = "PLAIN")]
+    Plain,
+    #[serde(rename = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[serde(rename = "SCRAM-SHA-512")]
+    ScramSha512,
+    /// The token comes from `sasl.oauthbearer.*` in `properties`.
+    #[serde(rename = "OAUTHBEARER")]
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    /// Value of the `sasl.mechanisms` property.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+            SaslMechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// PEM files of the TLS connection to the brokers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SslConfig {
+    /// CA certificates verifying the brokers, the system ones without it.
+    #[serde(default)]
+    pub ca_location: Option<String>,
+    /// Client certificate and key, for brokers authenticating clients by certificate.
+    #[serde(default)]
+    pub certificate_location: Option<String>,
+    #[serde(default)]
+    pub key_location: Option<String>,
+    #[serde(default)]
+    pub key_password: Option<String>,
+}
+
+/// Where the group starts reading a partition it has no committed offset of.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum StartFrom {
+    #[default]
+    Earliest,
+    Latest,
+    /// First message at or after this time, in milliseconds since the epoch. Partitions whose
+    /// offset cannot be looked up start from the latest message.
+    Timestamp(i64),
+    /// Offset of the first message of a partition, by topic and partition. Partitions not
+    /// listed start from the earliest message.
+    Offsets(Vec<(String, i32, i64)>),
+}
+
+impl FromStr for StartFrom {
+    type Err = String;
+
+    /// `earliest`, `latest`, an RFC 3339 time or `topic:partition=offset, ...`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim() {
+            "earliest" => return Ok(StartFrom::Earliest),
+            "latest" => return Ok(StartFrom::Latest),
+            _ => {}
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(text.trim()) {
+            return Ok(StartFrom::Timestamp(time.timestamp_millis()));
+        }
+        let offset = |item: &str| {
+            let (partition, offset) = item.split_once('=')?;
+            let (topic, partition) = partition.rsplit_once(':')?;
+            Some((topic.trim().to_owned(), partition.trim().parse().ok()?, offset.trim().parse().ok()?))
+        };
+        text.split(',')
+            .map(|item| offset(item).filter(|(topic, _, offset)| !topic.is_empty() && *offset >= 0))
+            .collect::<Option<Vec<_>>>()
+            .map(StartFrom::Offsets)
+            .ok_or_else(|| format!("{} is neither earliest, latest, an RFC 3339 time nor topic:partition=offset pairs", text))
+    }
+}
+
+impl TryFrom<String> for StartFrom {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl StartFrom {
+    /// Value of the `auto.offset.reset` property, the position of the partitions that are not
+    /// sought to.
+    pub fn reset(&self) -> &'static str {
+        match self {
+            StartFrom::Earliest | StartFrom::Offsets(_) => "earliest",
+            // Rather than replaying the whole topic when the lookup fails.
+            StartFrom::Latest | StartFrom::Timestamp(_) => "latest",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ParserConfig {
+    pub regex: String,
+    /// Field names assigned to the regex capture groups, in order.
+    #[serde(deserialize_with = "string_list")]
+    pub mapping: Vec<String>,
+    /// Fields carried by the JSON envelope of every message next to `log`, e.g. `env,
+    /// service_code`. Once listed, `validate` and the start refuse to map fields produced
+    /// neither by `mapping` nor by the envelope; unset, any envelope field can be mapped.
+    #[serde(default, deserialize_with = "optional_string_list")]
+    pub envelope: Option<Vec<String>>,
+    /// Threads a large batch is split across to be parsed, its order is kept.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SenderConfig {
+    /// Column name => parsed field name.
+    pub mapping: IndexMap<String, String>,
+    /// Column name => chrono format of the parsed field.
+    #[serde(default)]
+    pub date_format: HashMap<String, String>,
+    /// Column receiving every parsed field that is not mapped, e.g. a `Map(String, String)` or
+    /// `JSON` column.
+    #[serde(default)]
+    pub remaining_fields: Option<String>,
+    pub clickhouse: ClickhouseConfig,
+    /// Tables of the records matching a route, the first matching one applies. The other
+    /// records are written to `clickhouse.table`.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+/// Records of a topic and/or with given field values, and the table they are written to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Kafka topic of the records.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Parsed field => value the records have.
+    #[serde(default)]
+    pub when: IndexMap<String, String>,
+    /// Table name, where `${field}` is replaced by the value of a parsed field, e.g. `logs_${env}`.
+    pub table: String,
+    /// Replaces `sender.mapping`.
+    #[serde(default)]
+    pub mapping: Option<IndexMap<String, String>>,
+    /// Replaces `sender.date-format`.
+    #[serde(default)]
+    pub date_format: Option<HashMap<String, String>>,
+    /// Replaces `sender.remaining-fields`.
+    #[serde(default)]
+    pub remaining_fields: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClickhouseConfig {
+    /// HTTP endpoint, e.g. `http://localhost:8123`.
+    pub server: String,
+    pub database: String,
+    pub table: String,
+    #[serde(default = "default_clickhouse_username")]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// Record the written offsets in `offsets-table`, from which the consumer resumes on
+    /// partition assignment. Inserts are deduplicated by token, plain (non replicated) MergeTree
+    /// tables need `non_replicated_deduplication_window` set for it to take effect, which
+    /// `create-table` does. The offsets of a batch are recorded as an intent before its rows,
+    /// after a crash in between the batch is cut the same way again and its rows dropped.
+    #[serde(default)]
+    pub exactly_once: bool,
+    #[serde(default = "default_offsets_table")]
+    pub offsets_table: String,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub format: InsertFormat,
+    /// Create the table from `sender.mapping` when it does not exist, and add the columns mapped
+    /// later on; `create-table: {}` for the defaults.
+    #[serde(default)]
+    pub create_table: Option<TableConfig>,
+}
+
+/// DDL of the table generated from `sender.mapping`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TableConfig {
+    /// Column name => Clickhouse type. Other columns are `String`, `DateTime64(3)` with a
+    /// `date-format` and `Map(String, String)` for `remaining-fields`.
+    pub types: IndexMap<String, String>,
+    /// Column name => compression codec, e.g. `ZSTD(3)`.
+    pub codecs: IndexMap<String, String>,
+    pub engine: String,
+    /// Column the table is partitioned by day on, by default the first one with a `date-format`.
+    pub date_column: Option<String>,
+    /// Expression replacing the daily partitions.
+    pub partition_by: Option<String>,
+    /// Sorting key, by default the date column.
+    #[serde(deserialize_with = "string_list")]
+    pub order_by: Vec<String>,
+    /// e.g. `date + INTERVAL 30 DAY`.
+    pub ttl: Option<String>,
+}
+
+/// Encoding of the inserted rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InsertFormat {
+    /// Binary rows streamed in the request body; tables with column types it cannot encode
+    /// (e.g. Decimal) fall back to `values`.
+    #[default]
+    RowBinary,
+    /// An `INSERT ... VALUES` statement with every value bound as an SQL literal.
+    Values,
+}
+
+/// Retries of inserts failing for a transient reason (network, timeout, too many parts ...).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Milliseconds before the first retry, doubled after every failed attempt.
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+    /// Upper bound of the backoff in milliseconds.
+    #[serde(default = "default_retry_max_backoff")]
+    pub max_backoff: u64,
+    /// Consecutive failures after which the circuit opens and Kafka consumption is paused
+    /// until an insert succeeds again.
+    #[serde(default = "default_retry_threshold")]
+    pub threshold: u32,
+    /// Attempts after which a batch failing for a transient reason fails the pipeline, which is
+    /// then restarted; retried until it succeeds without it.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+/// What to do with a record a stage cannot handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorPolicy {
+    /// Stop the pipeline.
+    #[default]
+    Fail,
+    /// Drop the record.
+    Skip,
+    /// Drop the record and hand it to the dead-letter target.
+    DeadLetter,
+    /// Keep the record, with empty values for what the stage could not produce.
+    DefaultValue,
+}
+
+/// Error policy of every stage.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OnErrorConfig {
+    /// The payload is not a JSON object with a `log` string.
+    #[serde(default)]
+    pub json: ErrorPolicy,
+    /// The log does not match `parser.regex`.
+    #[serde(default)]
+    pub regex: ErrorPolicy,
+    /// A mapped field is missing.
+    #[serde(default)]
+    pub sink: ErrorPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DeadLetterConfig {
+    /// A topic on the cluster of `receive.kafka`.
+    Kafka {
+        topic: String,
+    },
+    /// A local NDJSON file, rotated into `<path>.1` .. `<path>.<max-files>`.
+    #[serde(rename_all = "kebab-case")]
+    File {
+        path: String,
+        #[serde(default = "default_file_max_size")]
+        max_size: u64,
+        #[serde(default = "default_file_max_files")]
+        max_files: usize,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Milliseconds allowed to deliver and commit the last batch after SIGTERM or SIGINT. The
+    /// process exits normally once they have passed, the batches not committed yet are received
+    /// again on the next start.
+    #[serde(default = "default_shutdown_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address serving `/metrics`, `/healthz` and `/readyz`, e.g. `0.0.0.0:9100`.
+    pub listen: String,
+    /// Milliseconds a pipeline may hold batches without delivering any before `/healthz` fails,
+    /// waiting for an unavailable sink excepted.
+    #[serde(default = "default_progress_timeout")]
+    pub progress_timeout: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            backoff: default_retry_backoff(),
+            max_backoff: default_retry_max_backoff(),
+            threshold: default_retry_threshold(),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        TableConfig {
+            types: IndexMap::new(),
+            codecs: IndexMap::new(),
+            engine: String::from("MergeTree"),
+            date_column: None,
+            partition_by: None,
+            order_by: Vec::new(),
+            ttl: None,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { timeout: default_shutdown_timeout() }
+    }
+}
+
+fn default_queue_size() -> usize {
+    2
+}
+
+fn default_best_effort_timeout() -> u64 {
+    30000
+}
+
+fn default_progress_timeout() -> u64 {
+    300000
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_batch_size() -> usize {
+    5000
+}
+
+fn default_batch_timeout() -> u64 {
+    3000
+}
+
+fn default_shutdown_timeout() -> u64 {
+    20000
+}
+
+fn default_retry_backoff() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff() -> u64 {
+    30000
+}
+
+fn default_retry_threshold() -> u32 {
+    3
+}
+
+fn default_file_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_file_max_files() -> usize {
+    5
+}
+
+fn default_clickhouse_username() -> String {
+    String::from("default")
+}
+
+fn default_offsets_table() -> String {
+    String::from("log2click_offsets")
+}
+
+/// Accepts either `a, b, c` or a YAML sequence.
+fn string_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringList {
+        Text(String),
+        List(Vec<String>),
+    }
+    let values = match StringList::deserialize(deserializer)? {
+        StringList::Text(text) => text.split(',').map(|it| it.trim().to_owned()).collect(),
+        StringList::List(list) => list.iter().map(|it| it.trim().to_owned()).collect::<Vec<String>>(),
+    };
+    Ok(values.into_iter().filter(|it| !it.is_empty()).collect())
+}
+
+fn optional_string_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    string_list(deserializer).map(Some)
+}
+
+/// A configuration problem, located by its YAML path and, when known, its line.
+#[derive(Clone, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.path.is_empty() && self.path != "." {
+            write!(f, "'{}' ", self.path)?;
+        }
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "(line {}, column {}) ", line, column)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn at(path: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError { path: path.into(), line: None, column: None, message: message.into() }
+    }
+}
+
+/// librdkafka properties the consumer relies on, with the reason they cannot be overridden.
+const MANAGED_PROPERTIES: [(&str, &str); 5] = [
+    ("bootstrap.servers", "set it with 'server'"),
+    ("group.id", "set it with 'group-id'"),
+    ("auto.offset.reset", "set it with 'start-from'"),
+    ("enable.auto.commit", "offsets are committed once their batch is delivered"),
+    ("enable.auto.offset.store", "offsets are committed once their batch is delivered"),
+];
+
+impl KafkaConfig {
+    /// The configured protocol, by default SASL when credentials are given.
+    pub fn protocol(&self) -> SecurityProtocol {
+        self.security_protocol.unwrap_or(match self.username {
+            Some(_) => SecurityProtocol::SaslPlaintext,
+            None => SecurityProtocol::Plaintext,
+        })
+    }
+
+    /// Problems located relative to the Kafka settings.
+    fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if self.size == 0 {
+            errors.push(ConfigError::at("size", "must be greater than 0"));
+        }
+        if self.timeout == 0 {
+            errors.push(ConfigError::at("timeout", "must be greater than 0"));
+        }
+        if self.topics.is_empty() {
+            errors.push(ConfigError::at("topics", "must name at least one topic"));
+        }
+        for (index, topic) in self.topics.iter().enumerate().filter(|(_, it)| it.starts_with('^')) {
+            if let Err(e) = Regex::new(topic) {
+                errors.push(ConfigError::at(format!("topics[{}]", index), e.to_string()));
+            }
+        }
+        let protocol = self.protocol();
+        let credentials = [("username", &self.username, "sasl.username"), ("password", &self.password, "sasl.password")];
+        for (key, value, property) in credentials {
+            match (protocol.is_sasl(), value) {
+                (false, Some(_)) => errors.push(ConfigError::at(key, format!(
+                    "is only used by the SASL protocols, not by {}", protocol.as_str()))),
+                (true, None) if self.sasl_mechanism != SaslMechanism::OAuthBearer && !self.properties.contains_key(property) => {
+                    errors.push(ConfigError::at(key, format!("is required by the {} mechanism", self.sasl_mechanism.as_str())))
+                }
+                _ => {}
+            }
+        }
+        if let Some(ssl) = &self.ssl {
+            if !protocol.is_ssl() {
+                errors.push(ConfigError::at("ssl", format!("is only used by the SSL protocols, not by {}", protocol.as_str())));
+            }
+            let files = [("ca-location", &ssl.ca_location), ("certificate-location", &ssl.certificate_location), ("key-location", &ssl.key_location)];
+            for (key, file) in files {
+                if let Some(file) = file.as_ref().filter(|it| !Path::new(it).is_file()) {
+                    errors.push(ConfigError::at(format!("ssl.{}", key), format!("{} is not a file", file)));
+                }
+            }
+            if ssl.certificate_location.is_some() != ssl.key_location.is_some() {
+                errors.push(ConfigError::at("ssl", "'certificate-location' and 'key-location' go together"));
+            }
+        }
+        for (property, reason) in MANAGED_PROPERTIES {
+            if self.properties.contains_key(property) {
+                errors.push(ConfigError::at(format!("properties.{}", property), format!("cannot be overridden, {}", reason)));
+            }
+        }
+        errors
+    }
+}
+
+impl SenderConfig {
+    /// Configuration of the records of `route`, its `table` being the target.
+    pub fn route(&self, route: &RouteConfig) -> SenderConfig {
+        let mut conf = self.clone();
+        conf.routes = Vec::new();
+        conf.clickhouse.table = route.table.to_owned();
+        if let Some(mapping) = &route.mapping {
+            conf.mapping = mapping.clone();
+        }
+        if let Some(date_format) = &route.date_format {
+            conf.date_format = date_format.clone();
+        }
+        if route.remaining_fields.is_some() {
+            conf.remaining_fields = route.remaining_fields.clone();
+        }
+        conf
+    }
+}
+
+impl SenderConfig {
+    /// Problems located relative to the sender.
+    fn validate(&self, parser: &ParserConfig) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let retry = &self.clickhouse.retry;
+        if retry.threshold == 0 {
+            errors.push(ConfigError::at("clickhouse.retry.threshold", "must be greater than 0"));
+        }
+        if retry.max_backoff < retry.backoff {
+            errors.push(ConfigError::at("clickhouse.retry.max-backoff", "must not be lower than 'backoff'"));
+        }
+        if retry.max_attempts == Some(0) {
+            errors.push(ConfigError::at("clickhouse.retry.max-attempts", "must be greater than 0"));
+        }
+        // Parsed by the filters, or describing the Kafka message. Without a declared envelope,
+        // any other field may come with the message.
+        let produced = |field: &str| match &parser.envelope {
+            Some(envelope) => {
+                std::iter::once("log").chain(parser.mapping.iter().chain(envelope).map(|it| it.as_str())).any(|it| it == field)
+                    || LogMessage::is_metadata(field)
+            }
+            None => true,
+        };
+        for (column, field) in &self.mapping {
+            if !produced(field) {
+                errors.push(ConfigError::at(format!("mapping.{}", column), format!(
+                    "field `{}` is produced neither by 'parser.mapping' nor by 'parser.envelope'", field)));
+            }
+        }
+        for (column, format) in &self.date_format {
+            let path = format!("date-format.{}", column);
+            if !self.mapping.contains_key(column) {
+                errors.push(ConfigError::at(&path, format!("column `{}` is not in 'mapping'", column)));
+            }
+            if let Err(message) = check_date_format(format) {
+                errors.push(ConfigError::at(&path, message));
+            }
+        }
+        if let Some(column) = self.remaining_fields.as_ref().filter(|it| self.mapping.contains_key(*it)) {
+            errors.push(ConfigError::at("remaining-fields", format!("column `{}` is in 'mapping' already", column)));
+        }
+        for (index, route) in self.routes.iter().enumerate() {
+            let path = format!("routes[{}]", index);
+            let conf = self.route(route);
+            for (column, field) in route.mapping.iter().flatten().filter(|(_, field)| !produced(field)) {
+                errors.push(ConfigError::at(format!("{}.mapping.{}", path, column), format!(
+                    "field `{}` is produced neither by 'parser.mapping' nor by 'parser.envelope'", field)));
+            }
+            for (column, format) in route.date_format.iter().flatten() {
+                let path = format!("{}.date-format.{}", path, column);
+                if !conf.mapping.contains_key(column) {
+                    errors.push(ConfigError::at(&path, format!("column `{}` is not in the mapping of the route", column)));
+                }
+                if let Err(message) = check_date_format(format) {
+                    errors.push(ConfigError::at(&path, message));
+                }
+            }
+            match template_fields(&route.table) {
+                Ok(fields) => {
+                    for field in fields.into_iter().filter(|it| !produced(it)) {
+                        errors.push(ConfigError::at(format!("{}.table", path), format!(
+                            "field `{}` is produced neither by 'parser.mapping' nor by 'parser.envelope'", field)));
+                    }
+                }
+                Err(message) => errors.push(ConfigError::at(format!("{}.table", path), message)),
+            }
+        }
+        if let Some(table) = &self.clickhouse.create_table {
+            // Columns of any table, routes included.
+            let known = |column: &String| {
+                std::iter::once(self.clone()).chain(self.routes.iter().map(|it| self.route(it)))
+                    .any(|it| it.mapping.contains_key(column) || it.remaining_fields.as_ref() == Some(column))
+            };
+            for (column, kind) in &table.types {
+                let path = format!("clickhouse.create-table.types.{}", column);
+                if !known(column) {
+                    errors.push(ConfigError::at(&path, format!("column `{}` is not in 'mapping'", column)));
+                }
+                if let Err(message) = kind.parse::<ColumnType>() {
+                    errors.push(ConfigError::at(&path, message));
+                }
+            }
+            for column in table.codecs.keys().filter(|it| !known(it)) {
+                errors.push(ConfigError::at(format!("clickhouse.create-table.codecs.{}", column),
+                                            format!("column `{}` is not in 'mapping'", column)));
+            }
+            if let Some(column) = table.date_column.as_ref().filter(|it| !known(it)) {
+                errors.push(ConfigError::at("clickhouse.create-table.date-column",
+                                            format!("column `{}` is not in 'mapping'", column)));
+            }
+        }
+        errors
+    }
+}
+
+/// Names of the fields a table template refers to, an error when a `${` is not closed.
+pub fn template_fields(template: &str) -> Result<Vec<&str>, String> {
+    let mut fields = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}')
+            .ok_or_else(|| format!("`{}` has an unterminated `${{`", template))?;
+        fields.push(&rest[start + 2..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+    Ok(fields)
+}
+
+impl Log2ClickConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Log2ClickConfig, SyncError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::at("", format!("cannot read {}: {}", path.display(), e)))?;
+        Ok(Log2ClickConfig::from_yaml(&text)?)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Log2ClickConfig, ConfigError> {
+        let document: Document = serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text)).map_err(|e| {
+            let path = e.path().to_string();
+            let message = e.inner().to_string();
+            // Errors raised on buffered values (e.g. tagged enums) carry no real location.
+            let location = e.inner().location().filter(|_| message.contains(" at line "));
+            // serde_yaml prefixes its own (parent) path and suffixes the location, both of
+            // which are reported separately.
+            let message = message.split(" at line ").next().unwrap_or_default();
+            let message = match message.split_once(": ") {
+                Some((prefix, rest)) if path.starts_with(prefix) => rest,
+                _ => message,
+            };
+            ConfigError {
+                path,
+                line: location.as_ref().map(|it| it.line()),
+                column: location.as_ref().map(|it| it.column()),
+                message: message.to_owned(),
+            }
+        })?;
+        let mut pipelines = document.pipelines;
+        for (index, pipeline) in pipelines.iter_mut().enumerate() {
+            pipeline.path = format!("pipelines[{}].", index);
+        }
+        let root = [document.receive.is_some(), document.parser.is_some(), document.sender.is_some(),
+            document.outputs.is_some(), document.commit.is_some(), document.best_effort_timeout.is_some(),
+            document.queue_size.is_some(), document.on_error.is_some(), document.dead_letter.is_some()];
+        if !pipelines.is_empty() {
+            if root.contains(&true) {
+                return Err(ConfigError::at("pipelines", "cannot be combined with a pipeline configured at the root"));
+            }
+            return Ok(Log2ClickConfig { pipelines, shutdown: document.shutdown, http: document.http });
+        }
+        // The single pipeline of a configuration without `pipelines`.
+        let missing = |field: &str| ConfigError::at("", format!("missing field `{}`", field));
+        let pipeline = PipelineConfig {
+            name: String::from("default"),
+            receive: document.receive.ok_or_else(|| missing("receive"))?,
+            parser: document.parser.ok_or_else(|| missing("parser"))?,
+            sender: document.sender.ok_or_else(|| missing("sender"))?,
+            outputs: document.outputs.unwrap_or_default(),
+            commit: document.commit.unwrap_or_default(),
+            best_effort_timeout: document.best_effort_timeout.unwrap_or_else(default_best_effort_timeout),
+            queue_size: document.queue_size.unwrap_or_else(default_queue_size),
+            on_error: document.on_error.unwrap_or_default(),
+            dead_letter: document.dead_letter,
+            path: String::new(),
+        };
+        Ok(Log2ClickConfig { pipelines: vec![pipeline], shutdown: document.shutdown, http: document.http })
+    }
+
+    /// The pipeline of a name, without a name the only one.
+    pub fn pipeline(&self, name: Option<&str>) -> Result<&PipelineConfig, SyncError> {
+        match (name, self.pipelines.as_slice()) {
+            (None, [pipeline]) => Ok(pipeline),
+            (None, _) => Err(SyncError::OptionParams(String::from("Several pipelines are configured, choose one with --pipeline."))),
+            (Some(name), pipelines) => pipelines.iter().find(|it| it.name == name)
+                .ok_or_else(|| SyncError::OptionParams(format!("Pipeline '{}' is not configured.", name))),
+        }
+    }
+
+    /// Checks that need more than the YAML structure, without touching the network.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        for (index, pipeline) in self.pipelines.iter().enumerate() {
+            if self.pipelines[..index].iter().any(|it| it.name == pipeline.name) {
+                errors.push(ConfigError::at(format!("{}name", pipeline.path), format!("pipeline `{}` is configured twice", pipeline.name)));
+            }
+            errors.extend(pipeline.validate());
+        }
+        if let Some(http) = &self.http {
+            if let Err(e) = http.listen.parse::<SocketAddr>() {
+                errors.push(ConfigError::at("http.listen", e.to_string()));
+            }
+        }
+        errors
+    }
+}
+
+impl PipelineConfig {
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        for error in self.receive.kafka.validate() {
+            errors.push(ConfigError { path: format!("receive.kafka.{}", error.path), ..error });
+        }
+        if self.queue_size == 0 {
+            errors.push(ConfigError::at("queue-size", "must be greater than 0"));
+        }
+        if self.best_effort_timeout == 0 {
+            errors.push(ConfigError::at("best-effort-timeout", "must be greater than 0"));
+        }
+
+        let parser = &self.parser;
+        if parser.workers == 0 {
+            errors.push(ConfigError::at("parser.workers", "must be greater than 0"));
+        }
+        match Regex::new(&parser.regex) {
+            Ok(regex) => {
+                let groups = regex.captures_len() - 1;
+                // The extra groups are left unmapped.
+                if groups < parser.mapping.len() {
+                    errors.push(ConfigError::at("parser.mapping", format!(
+                        "names {} fields but 'parser.regex' has {} capture groups", parser.mapping.len(), groups)));
+                }
+            }
+            Err(e) => errors.push(ConfigError::at("parser.regex", e.to_string())),
+        }
+
+        for error in self.sender.validate(parser) {
+            errors.push(ConfigError { path: format!("sender.{}", error.path), ..error });
+        }
+        for (index, output) in self.outputs.iter().enumerate() {
+            match output {
+                OutputConfig::Clickhouse { sender, .. } => {
+                    for error in sender.validate(parser) {
+                        errors.push(ConfigError { path: format!("outputs[{}].sender.{}", index, error.path), ..error });
+                    }
+                }
+                OutputConfig::File { max_files, .. } if *max_files == 0 => {
+                    errors.push(ConfigError::at(format!("outputs[{}].max-files", index), "must be greater than 0, \
+                        the archive would be deleted each time it is rotated"));
+                }
+                OutputConfig::File { .. } => {}
+            }
+        }
+        if let Some(DeadLetterConfig::File { max_files: 0, .. }) = &self.dead_letter {
+            errors.push(ConfigError::at("dead-letter.max-files", "must be greater than 0, \
+                the dead letters would be deleted each time the file is rotated"));
+        }
+        let exactly_once = self.sender.clickhouse.exactly_once || self.outputs.iter()
+            .any(|it| matches!(it, OutputConfig::Clickhouse { sender, .. } if sender.clickhouse.exactly_once));
+        if exactly_once && !self.outputs.is_empty() && self.commit != CommitPolicy::All {
+            errors.push(ConfigError::at("commit", "must be `all` with an `exactly-once` sink, \
+                the group would be committed past the offsets its table recorded"));
+        }
+        for error in errors.iter_mut() {
+            error.path.insert_str(0, &self.path);
+        }
+        errors
+    }
+}
+
+/// A date format must be valid for chrono and describe a full date, with or without a time:
+/// `Date` columns take date-only formats, the others the day at midnight.
+fn check_date_format(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|it| matches!(it, Item::Error)) {
+        return Err(format!("`{}` is not a valid chrono format", format));
+    }
+    let sample = NaiveDate::from_ymd_opt(2024, 3, 19).unwrap_or_default()
+        .and_hms_milli_opt(19, 1, 32, 737).unwrap_or_default();
+    let text = sample.format(format).to_string();
+    NaiveDateTime::parse_from_str(&text, format)
+        .map(|_| ())
+        .or_else(|_| NaiveDate::parse_from_str(&text, format).map(|_| ()))
+        .map_err(|e| format!("`{}` cannot be parsed back into a date: {}", format, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group_id: log2click
+    username: admin
+    password: admin
+parser:
+  regex: '(\S+) (\S+)'
+  mapping: date, message
+sender:
+  mapping:
+    date: date
+    message: message
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+"#;
+
+    #[test]
+    fn defaults() {
+        let conf = Log2ClickConfig::from_yaml(CONFIG).unwrap().pipelines.remove(0);
+        assert_eq!(conf.name, "default");
+        assert_eq!(conf.receive.kafka.size, 5000);
+        assert_eq!(conf.receive.kafka.topics, vec!["logs"]);
+        assert_eq!(conf.parser.mapping, vec!["date", "message"]);
+        assert_eq!(conf.parser.workers, 1);
+        assert_eq!(conf.sender.mapping.keys().collect::<Vec<_>>(), vec!["date", "message"]);
+        assert_eq!(conf.sender.clickhouse.username, "default");
+    }
+
+    #[test]
+    fn unknown_key_is_located() {
+        let text = CONFIG.replace("    table: app", "    table: app\n    tabel: app");
+        let error = Log2ClickConfig::from_yaml(&text).unwrap_err();
+        assert_eq!(error.path, "sender.clickhouse.tabel");
+        assert_eq!(error.line, Some(20));
+        assert!(error.message.starts_with("unknown field `tabel`"), "{}", error.message);
+    }
+
+    #[test]
+    fn validate() {
+        let text = CONFIG
+            .replace("topic: logs", "topics: [logs, '^app-(']")
+            .replace("  mapping: date, message", "  mapping: date, message\n  envelope: env")
+            .replace("    message: message", "    message: message\n    level: level\n    topic: _kafka.topic")
+            .replace("  clickhouse:", "  date-format:\n    date: '%H:%M:%S'\n  clickhouse:");
+        let errors = Log2ClickConfig::from_yaml(&text).unwrap().validate();
+        let paths: Vec<&str> = errors.iter().map(|it| it.path.as_str()).collect();
+        assert_eq!(paths, vec!["receive.kafka.topics[1]", "sender.mapping.level", "sender.date-format.date"]);
+        assert!(Log2ClickConfig::from_yaml(CONFIG).unwrap().validate().is_empty());
+        // Envelope fields are only checked once the envelope is declared.
+        let text = CONFIG.replace("    message: message", "    message: message\n    env: env\n    service: service_code");
+        assert!(Log2ClickConfig::from_yaml(&text).unwrap().validate().is_empty());
+        let text = text.replace("  mapping: date, message", "  mapping: date, message\n  envelope: env");
+        let errors = Log2ClickConfig::from_yaml(&text).unwrap().validate();
+        assert_eq!(errors.iter().map(|it| it.path.as_str()).collect::<Vec<_>>(), vec!["sender.mapping.service"]);
+        // Capture groups may be left unmapped, but not mapped fields without a group.
+        let text = CONFIG.replace("  mapping: date, message", "  mapping: date, message, level");
+        let errors = Log2ClickConfig::from_yaml(&text).unwrap().validate();
+        assert_eq!(errors.iter().map(|it| it.path.as_str()).collect::<Vec<_>>(), vec!["parser.mapping"]);
+        // Date columns take date-only formats.
+        let text = CONFIG.replace("  clickhouse:", "  date-format:\n    date: '%Y-%m-%d'\n  clickhouse:");
+        assert!(Log2ClickConfig::from_yaml(&text).unwrap().validate().is_empty());
+    }
+
+    #[test]
+    fn kafka_security() {
+        let kafka = |settings: &str| {
+            let text = CONFIG.replace("    username: admin\n    password: admin\n", settings);
+            Log2ClickConfig::from_yaml(&text).unwrap().pipelines.remove(0)
+        };
+        let conf = kafka("");
+        assert_eq!(conf.receive.kafka.protocol(), SecurityProtocol::Plaintext);
+        assert!(conf.validate().is_empty());
+        // Credentials alone keep meaning PLAIN over SASL_PLAINTEXT.
+        assert_eq!(Log2ClickConfig::from_yaml(CONFIG).unwrap().pipelines[0].receive.kafka.protocol(), SecurityProtocol::SaslPlaintext);
+
+        let conf = kafka("    security-protocol: SASL_SSL\n    sasl-mechanism: SCRAM-SHA-512\n    username: admin\n    \
+            ssl:\n      ca-location: /nonexistent/ca.pem\n      key-location: /nonexistent/key.pem\n    \
+            properties:\n      group.id: other\n      fetch.max.bytes: '1048576'\n");
+        let paths: Vec<String> = conf.validate().into_iter().map(|it| it.path).collect();
+        assert_eq!(paths, vec!["receive.kafka.password", "receive.kafka.ssl.ca-location", "receive.kafka.ssl.key-location",
+            "receive.kafka.ssl", "receive.kafka.properties.group.id"]);
+        assert!(kafka("    security-protocol: SSL\n    username: admin\n").validate().iter().any(|it| it.path == "receive.kafka.username"));
+    }
+
+    #[test]
+    fn start_from() {
+        let start_from = |value: &str| {
+            let text = CONFIG.replace("    topic: logs\n", &format!("    topic: logs\n    start-from: '{}'\n", value));
+            Log2ClickConfig::from_yaml(&text).map(|mut it| it.pipelines.remove(0).receive.kafka.start_from)
+        };
+        assert_eq!(Log2ClickConfig::from_yaml(CONFIG).unwrap().pipelines[0].receive.kafka.start_from, StartFrom::Earliest);
+        assert_eq!(start_from("latest").unwrap(), StartFrom::Latest);
+        assert_eq!(start_from("2024-05-01T02:00:00+02:00").unwrap(), StartFrom::Timestamp(1714521600000));
+        assert_eq!(start_from("logs:0=1200, app.logs:1=0").unwrap(), StartFrom::Offsets(vec![
+            (String::from("logs"), 0, 1200), (String::from("app.logs"), 1, 0)]));
+        let error = start_from("yesterday").unwrap_err();
+        assert_eq!((error.path.as_str(), error.line), ("receive.kafka.start-from", Some(4)));
+        assert!(start_from("logs:0=-1").is_err());
+        let resets: Vec<&str> = [StartFrom::Earliest, StartFrom::Latest, StartFrom::Timestamp(0), StartFrom::Offsets(Vec::new())]
+            .iter().map(StartFrom::reset).collect();
+        assert_eq!(resets, vec!["earliest", "latest", "latest", "earliest"]);
+
+        let text = CONFIG.replace("    username: admin\n", "    username: admin\n    properties:\n      auto.offset.reset: latest\n");
+        let paths: Vec<String> = Log2ClickConfig::from_yaml(&text).unwrap().validate().into_iter().map(|it| it.path).collect();
+        assert_eq!(paths, vec!["receive.kafka.properties.auto.offset.reset"]);
+    }
+
+    #[test]
+    fn exactly_once_commits_all() {
+        let text = CONFIG.replace("    table: app", "    table: app\n    exactly-once: true")
+            + "outputs:\n  - type: file\n    path: /var/log/log2click/archive.ndjson\n";
+        assert!(Log2ClickConfig::from_yaml(&text).unwrap().validate().is_empty());
+        for commit in ["any", "primary"] {
+            let conf = Log2ClickConfig::from_yaml(&format!("{}commit: {}\n", text, commit)).unwrap();
+            let paths: Vec<String> = conf.validate().into_iter().map(|it| it.path).collect();
+            assert_eq!(paths, vec!["commit"]);
+        }
+    }
+
+    #[test]
+    fn archive_keeps_rotated_files() {
+        let text = CONFIG.to_owned() + "outputs:\n  - type: file\n    path: /var/log/log2click/archive.ndjson\n    max-files: 0\n";
+        let paths: Vec<String> = Log2ClickConfig::from_yaml(&text).unwrap().validate().into_iter().map(|it| it.path).collect();
+        assert_eq!(paths, vec!["outputs[0].max-files"]);
+    }
+
+    #[test]
+    fn dead_letter_keeps_rotated_files() {
+        let text = CONFIG.to_owned() + "dead-letter:\n  type: file\n  path: /var/log/log2click/dead-letter.ndjson\n  max-files: 0\n";
+        let paths: Vec<String> = Log2ClickConfig::from_yaml(&text).unwrap().validate().into_iter().map(|it| it.path).collect();
+        assert_eq!(paths, vec!["dead-letter.max-files"]);
+    }
+
+    #[test]
+    fn pipelines() {
+        let pipeline = |name: &str| format!("  - name: {}\n    {}", name, CONFIG.trim_start().replace('\n', "\n    "));
+        let text = format!("pipelines:\n{}\n{}", pipeline("app"), pipeline("app").replace("table: app", "table: app\n        tabel: app"));
+        let error = Log2ClickConfig::from_yaml(&text).unwrap_err();
+        assert_eq!(error.path, "pipelines[1].sender.clickhouse.tabel");
+
+        let text = format!("pipelines:\n{}\n{}", pipeline("app"), pipeline("app").replace("mapping: date, message", "mapping: date\n      envelope: env"));
+        let conf = Log2ClickConfig::from_yaml(&text).unwrap();
+        let paths: Vec<String> = conf.validate().into_iter().map(|it| it.path).collect();
+        assert_eq!(paths, vec!["pipelines[1].name", "pipelines[1].sender.mapping.message"]);
+        assert!(conf.pipeline(None).is_err());
+        assert_eq!(conf.pipeline(Some("app")).unwrap().sender.clickhouse.table, "app");
+
+        let text = format!("{}pipelines:\n{}", CONFIG, pipeline("app"));
+        assert_eq!(Log2ClickConfig::from_yaml(&text).unwrap_err().path, "pipelines");
+    }
+}
@@ -0,0 +1,194 @@
+use crate::config::{SenderConfig, TableConfig};
+use crate::error::SyncError;
+use crate::schema::ColumnType;
+
+/// Type of a mapped column in the generated table: the one of `create-table.types`, otherwise
+/// derived from how the column is filled.
+pub fn column_type(conf: &SenderConfig, column: &str) -> Result<ColumnType, SyncError> {
+    let configured = conf.clickhouse.create_table.as_ref().and_then(|it| it.types.get(column));
+    if let Some(kind) = configured {
+        return kind.parse().map_err(SyncError::OptionParams);
+    }
+    let map = || ColumnType::Map(Box::new(ColumnType::String), Box::new(ColumnType::String));
+    if conf.remaining_fields.as_deref() == Some(column) {
+        return Ok(map());
+    }
+    Ok(match conf.mapping.get(column).map(|it| it.as_str()) {
+        Some("_kafka.partition") => ColumnType::Int(32),
+        Some("_kafka.offset") => ColumnType::Int(64),
+        Some("_kafka.timestamp") => ColumnType::DateTime64(3, None),
+        Some("_kafka.headers") => map(),
+        _ if conf.date_format.contains_key(column) => ColumnType::DateTime64(3, None),
+        _ => ColumnType::String,
+    })
+}
+
+/// Inserted blocks whose deduplication token a plain MergeTree table remembers, without which
+/// it ignores the tokens of the retried and redelivered batches.
+const DEDUPLICATION_WINDOW: u64 = 1000;
+
+/// Table generated from `sender.mapping` and `sender.clickhouse.create-table`.
+pub struct TableSchema {
+    table: String,
+    /// Name, type and codec of every column, in the order of the mapping.
+    columns: Vec<(String, ColumnType, Option<String>)>,
+    engine: String,
+    partition_by: Option<String>,
+    order_by: String,
+    ttl: Option<String>,
+    settings: Vec<String>,
+}
+
+impl TableSchema {
+    pub fn create(conf: &SenderConfig) -> Result<TableSchema, SyncError> {
+        let default = TableConfig::default();
+        let table = conf.clickhouse.create_table.as_ref().unwrap_or(&default);
+        let mut columns = Vec::with_capacity(conf.mapping.len() + 1);
+        for name in conf.mapping.keys().chain(&conf.remaining_fields) {
+            columns.push((name.to_owned(), column_type(conf, name)?, table.codecs.get(name).cloned()));
+        }
+        // The first date column of the mapping, unless another one is named.
+        let date = table.date_column.as_ref()
+            .or_else(|| conf.mapping.keys().find(|it| conf.date_format.contains_key(*it)));
+        let order_by = match (table.order_by.as_slice(), date) {
+            ([], Some(date)) => date.to_owned(),
+            ([], None) => String::from("tuple()"),
+            ([key], _) => key.to_owned(),
+            (keys, _) => format!("({})", keys.join(", ")),
+        };
+        // Replicated tables deduplicate by default.
+        let plain_merge_tree = table.engine.contains("MergeTree") && !table.engine.starts_with("Replicated");
+        let mut settings = Vec::new();
+        if conf.clickhouse.exactly_once && plain_merge_tree {
+            settings.push(format!("non_replicated_deduplication_window = {}", DEDUPLICATION_WINDOW));
+        }
+        Ok(TableSchema {
+            table: conf.clickhouse.table.to_owned(),
+            columns,
+            engine: table.engine.to_owned(),
+            partition_by: table.partition_by.clone().or_else(|| date.map(|it| format!("toDate({})", it))),
+            order_by,
+            ttl: table.ttl.clone(),
+            settings,
+        })
+    }
+
+    fn column(&self, name: &str, kind: &ColumnType, codec: &Option<String>) -> String {
+        match codec {
+            Some(codec) => format!("{} {} CODEC({})", name, kind, codec),
+            None => format!("{} {}", name, kind),
+        }
+    }
+
+    pub fn create_table(&self) -> String {
+        let columns: Vec<String> = self.columns.iter()
+            .map(|(name, kind, codec)| format!("    {}", self.column(name, kind, codec)))
+            .collect();
+        let mut sql = format!("CREATE TABLE IF NOT EXISTS {}\n(\n{}\n)\nENGINE = {}", self.table, columns.join(",\n"), self.engine);
+        if let Some(partition_by) = &self.partition_by {
+            sql.push_str(&format!("\nPARTITION BY {}", partition_by));
+        }
+        sql.push_str(&format!("\nORDER BY {}", self.order_by));
+        if let Some(ttl) = &self.ttl {
+            sql.push_str(&format!("\nTTL {}", ttl));
+        }
+        if !self.settings.is_empty() {
+            sql.push_str(&format!("\nSETTINGS {}", self.settings.join(", ")));
+        }
+        sql
+    }
+
+    /// `ALTER TABLE` statements adding the columns for which `exists` is `false`.
+    pub fn add_columns(&self, exists: impl Fn(&str) -> bool) -> Vec<String> {
+        self.columns.iter()
+            .filter(|(name, _, _)| !exists(name))
+            .map(|(name, kind, codec)| {
+                format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {}", self.table, self.column(name, kind, codec))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Log2ClickConfig;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group_id: log2click
+    username: admin
+    password: admin
+parser:
+  regex: '(\S+) (\S+) (.*)'
+  mapping: date, level, message
+sender:
+  mapping:
+    date: date
+    level: level
+    message: message
+    offset: _kafka.offset
+  date-format:
+    date: '%Y-%m-%d %H:%M:%S%.3f'
+  remaining-fields: extra
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+    create-table:
+      types:
+        level: LowCardinality(String)
+      codecs:
+        message: ZSTD(3)
+      order-by: level, date
+      ttl: toDate(date) + INTERVAL 30 DAY
+"#;
+
+    #[test]
+    fn create_table() -> Result<(), SyncError> {
+        let conf = Log2ClickConfig::from_yaml(CONFIG)?;
+        assert_eq!(TableSchema::create(&conf.pipelines[0].sender)?.create_table(), "\
+CREATE TABLE IF NOT EXISTS app
+(
+    date DateTime64(3),
+    level LowCardinality(String),
+    message String CODEC(ZSTD(3)),
+    offset Int64,
+    extra Map(String, String)
+)
+ENGINE = MergeTree
+PARTITION BY toDate(date)
+ORDER BY (level, date)
+TTL toDate(date) + INTERVAL 30 DAY");
+        Ok(())
+    }
+
+    #[test]
+    fn deduplicate_exactly_once_inserts() -> Result<(), SyncError> {
+        let text = CONFIG.replace("    table: app\n", "    table: app\n    exactly-once: true\n");
+        let conf = Log2ClickConfig::from_yaml(&text)?;
+        let sql = TableSchema::create(&conf.pipelines[0].sender)?.create_table();
+        assert!(sql.ends_with("TTL toDate(date) + INTERVAL 30 DAY\nSETTINGS non_replicated_deduplication_window = 1000"), "{}", sql);
+
+        let text = text.replace("      ttl:", "      engine: ReplicatedMergeTree\n      ttl:");
+        let conf = Log2ClickConfig::from_yaml(&text)?;
+        assert!(!TableSchema::create(&conf.pipelines[0].sender)?.create_table().contains("SETTINGS"));
+        Ok(())
+    }
+
+    #[test]
+    fn add_new_columns() -> Result<(), SyncError> {
+        let conf = Log2ClickConfig::from_yaml(CONFIG)?;
+        let statements = TableSchema::create(&conf.pipelines[0].sender)?.add_columns(|name| name == "date" || name == "level");
+        assert_eq!(statements, vec![
+            "ALTER TABLE app ADD COLUMN IF NOT EXISTS message String CODEC(ZSTD(3))",
+            "ALTER TABLE app ADD COLUMN IF NOT EXISTS offset Int64",
+            "ALTER TABLE app ADD COLUMN IF NOT EXISTS extra Map(String, String)",
+        ]);
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Take};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::{DeadLetterTrait, LogMessage, Offsets, ReceiveTrait};
+use crate::archive::RotatingFile;
+use crate::config::KafkaConfig;
+use crate::error::SyncError;
+use crate::policy::DeadLetter;
+use crate::source::Kafka;
+
+/// Publishes dead letters as JSON to a Kafka topic.
+pub struct KafkaDeadLetter {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaDeadLetter {
+    pub fn create(conf: &KafkaConfig, topic: &str) -> Result<KafkaDeadLetter, SyncError> {
+        info!("[DeadLetter] Rejected records are published to topic {}", topic);
+        let producer: FutureProducer = Kafka::client_config(conf, &[]).create()?;
+        Ok(KafkaDeadLetter { producer, topic: topic.to_owned() })
+    }
+}
+
+#[async_trait]
+impl DeadLetterTrait for KafkaDeadLetter {
+    async fn send(&self, letter: DeadLetter) -> Result<(), SyncError> {
+        let payload = serde_json::to_string(&letter)?;
+        let key = format!("{}-{}@{}", letter.topic, letter.partition, letter.offset);
+        let record = FutureRecord::to(&self.topic).payload(&payload).key(&key);
+        self.producer.send(record, Duration::from_secs(30)).await.map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
+
+/// Appends dead letters to an NDJSON file, rotated once it reaches `max_size` bytes.
+pub struct FileDeadLetter {
+    file: Arc<RotatingFile>,
+}
+
+impl FileDeadLetter {
+    pub fn create(path: &str, max_size: u64, max_files: usize) -> FileDeadLetter {
+        info!("[DeadLetter] Rejected records are written to {}", path);
+        FileDeadLetter { file: Arc::new(RotatingFile::new(path, max_size, max_files)) }
+    }
+}
+
+#[async_trait]
+impl DeadLetterTrait for FileDeadLetter {
+    async fn send(&self, letter: DeadLetter) -> Result<(), SyncError> {
+        let mut line = serde_json::to_string(&letter)?;
+        line.push('\n');
+        self.file.clone().write(line).await
+    }
+}
+
+/// Source reading a dead-letter file back, so its records can go through the pipeline again.
+///
+/// Only the content present when the file was opened is read, records rejected again while
+/// replaying are not picked up a second time.
+pub struct Replay {
+    lines: Mutex<std::io::Lines<BufReader<Take<File>>>>,
+    size: usize,
+}
+
+impl Replay {
+    pub fn create(path: &str, size: usize) -> Result<Replay, SyncError> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(Replay { lines: Mutex::new(BufReader::new(file.take(length)).lines()), size })
+    }
+}
+
+#[async_trait]
+impl ReceiveTrait for Replay {
+    async fn pull(&self) -> Result<Vec<LogMessage>, SyncError> {
+        let mut lines = self.lines.lock().map_err(|_| SyncError::Option)?;
+        let mut batch = Vec::new();
+        while batch.len() < self.size {
+            let line = match lines.next() {
+                None => break,
+                Some(line) => line?,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<DeadLetter>(&line) {
+                Ok(letter) => batch.push(LogMessage {
+                    key: letter.key,
+                    timestamp: letter.message_timestamp,
+                    headers: letter.headers,
+                    ..LogMessage::new(letter.topic, letter.partition, letter.offset, letter.body)
+                }),
+                Err(e) => warn!("[Replay] Not a dead letter, ignored: {}", e),
+            }
+        }
+        Ok(batch)
+    }
+
+    async fn confirm(&self, offsets: &Offsets) -> Result<(), SyncError> {
+        info!("[Replay] Replayed up to {:?}", offsets);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::policy::Stage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rotated_file_is_replayed() -> Result<(), SyncError> {
+        let dir = std::env::temp_dir().join(format!("log2click-dead-letter-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("rejected.ndjson");
+        let target = FileDeadLetter::create(path.to_str().unwrap(), 1, 1);
+        for offset in 0..3 {
+            let message = LogMessage::new("logs", 0, offset, format!("body {}", offset));
+            target.send(DeadLetter::create(&message, Stage::Json, &SyncError::Option)).await?;
+        }
+        // Every letter fills a file, only the newest rotated one is kept.
+        assert!(!path.exists());
+        let replay = Replay::create(target.file.rotated(1).to_str().unwrap(), 10)?;
+        let batch = replay.pull().await?;
+        assert_eq!(batch.iter().map(|it| it.body.as_str()).collect::<Vec<_>>(), vec!["body 2"]);
+        assert!(replay.pull().await?.is_empty());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -3,7 +3,10 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum SyncError {
     #[error("ConfigError: {0}")]
-    ConfigError(#[from] config::ConfigError),
+    ConfigError(#[from] crate::config::ConfigError),
+
+    #[error("{0}")]
+    IoError(#[from] std::io::Error),
 
     #[error("PipBuilderError: {0}")]
     PipBuilderError(#[from] crate::PipBuilderError),
@@ -23,6 +26,9 @@ pub enum SyncError {
     #[error("{0}")]
     ClickhouseError(#[from] clickhouse::error::Error),
 
+    #[error("{0}")]
+    HttpError(#[from] hyper::Error),
+
     #[error("None occurred")]
     Option,
 
@@ -31,4 +37,82 @@ pub enum SyncError {
 
     #[error("{0}")]
     OptionParams(String),
-}
\ No newline at end of file
+}
+/// Clickhouse error codes of conditions expected to clear up by themselves.
+const RETRYABLE_CODES: [u32; 11] = [
+    3,   // UNEXPECTED_END_OF_FILE
+    159, // TIMEOUT_EXCEEDED
+    164, // READONLY
+    202, // TOO_MANY_SIMULTANEOUS_QUERIES
+    209, // SOCKET_TIMEOUT
+    210, // NETWORK_ERROR
+    242, // TABLE_IS_READ_ONLY
+    252, // TOO_MANY_PARTS
+    319, // UNKNOWN_STATUS_OF_INSERT
+    425, // SYSTEM_ERROR
+    999, // KEEPER_EXCEPTION
+];
+
+impl SyncError {
+    /// Whether the same request may succeed later, e.g. Clickhouse restarting or merging
+    /// parts, as opposed to errors in the data or the schema.
+    pub fn is_retryable(&self) -> bool {
+        use clickhouse::error::Error;
+        match self {
+            SyncError::ClickhouseError(Error::Network(_) | Error::TimedOut) => true,
+            SyncError::ClickhouseError(Error::BadResponse(response)) => {
+                match response.strip_prefix("Code: ").and_then(|it| it.split('.').next()) {
+                    Some(code) => code.parse().is_ok_and(|code| RETRYABLE_CODES.contains(&code)),
+                    // No exception, only the HTTP status, e.g. from a proxy in front of Clickhouse.
+                    None => response.starts_with('5') || response.starts_with("429"),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clickhouse::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn retryable_clickhouse_errors() {
+        let response = |text: &str| SyncError::ClickhouseError(Error::BadResponse(text.to_owned()));
+        let cases = [
+            (response("Code: 3. DB::Exception: Unexpected end of file while reading chunk header."), true),
+            (response("Code: 159. DB::Exception: Timeout exceeded: elapsed 30.001 seconds."), true),
+            (response("Code: 164. DB::Exception: Cannot execute query in readonly mode."), true),
+            (response("Code: 202. DB::Exception: Too many simultaneous queries. Maximum: 100."), true),
+            (response("Code: 209. DB::Exception: Timeout exceeded while reading from socket."), true),
+            (response("Code: 210. DB::NetException: Connection refused."), true),
+            (response("Code: 242. DB::Exception: Table is in readonly mode."), true),
+            (response("Code: 252. DB::Exception: Too many parts (300)."), true),
+            (response("Code: 319. DB::Exception: Unknown status, client must retry."), true),
+            (response("Code: 425. DB::ErrnoException: Cannot open file."), true),
+            (response("Code: 999. Coordination::Exception: Connection loss."), true),
+            (response("503 Service Unavailable"), true),
+            (response("502 Bad Gateway"), true),
+            (response("429 Too Many Requests"), true),
+            (SyncError::ClickhouseError(Error::TimedOut), true),
+            (SyncError::ClickhouseError(Error::Network(Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset)))), true),
+            (response("Code: 16. DB::Exception: No such column level in table logs.app."), false),
+            (response("Code: 27. DB::ParsingException: Cannot parse input: expected '\\t' before: 'x'."), false),
+            (response("Code: 53. DB::Exception: Type mismatch in VALUES section."), false),
+            (response("Code: 60. DB::Exception: Table logs.app does not exist."), false),
+            (response("Code: 62. DB::Exception: Syntax error: failed at position 1."), false),
+            (response("Code: 516. DB::Exception: default: Authentication failed."), false),
+            // Not the code of TOO_MANY_PARTS, only its prefix.
+            (response("Code: 2520. DB::Exception: Unknown."), false),
+            (response("404 Not Found"), false),
+            (response("401 Unauthorized"), false),
+            (SyncError::ClickhouseError(Error::NotEnoughData), false),
+            (SyncError::OptionParams(String::from("Code: 252.")), false),
+        ];
+        for (error, retryable) in cases {
+            assert_eq!(error.is_retryable(), retryable, "{}", error);
+        }
+    }
+}
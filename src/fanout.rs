@@ -0,0 +1,260 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::future::select_all;
+use futures::stream::FuturesUnordered;
+use log::warn;
+use tokio::sync::watch;
+use tokio::time::{Instant, timeout_at};
+
+use crate::{LogMessage, SendTrait};
+use crate::config::CommitPolicy;
+use crate::error::SyncError;
+
+/// Sink writing every batch to several sinks concurrently. The batch is written, and can be
+/// committed, once the sinks required by the commit policy have written it; the others are
+/// given until `best_effort` after the start of the batch to write it too.
+pub struct FanOut {
+    /// Name, sink and whether it is primary.
+    sinks: Vec<(String, Arc<dyn SendTrait>, bool)>,
+    commit: CommitPolicy,
+    best_effort: Duration,
+}
+
+impl FanOut {
+    pub fn new(commit: CommitPolicy, best_effort: Duration) -> FanOut {
+        FanOut { sinks: Vec::new(), commit, best_effort }
+    }
+
+    pub fn add(&mut self, name: &str, sink: Arc<dyn SendTrait>, primary: bool) {
+        self.sinks.push((name.to_owned(), sink, primary));
+    }
+
+    /// Whether a failure of the sink at `index` prevents the commit, under `any` once every
+    /// sink failed.
+    fn required(&self, index: usize) -> bool {
+        match self.commit {
+            CommitPolicy::All | CommitPolicy::Any => true,
+            CommitPolicy::Primary => self.sinks[index].2,
+        }
+    }
+
+    /// `Some(true)` once the policy is satisfied by the results so far, `Some(false)` once it
+    /// cannot be anymore, nothing while it depends on the sinks still writing.
+    fn decided(&self, results: &[Option<Result<(), SyncError>>]) -> Option<bool> {
+        let written = |result: &Option<Result<(), SyncError>>| matches!(result, Some(Ok(())));
+        let failed = |result: &Option<Result<(), SyncError>>| matches!(result, Some(Err(_)));
+        match self.commit {
+            CommitPolicy::Any if results.iter().any(written) => Some(true),
+            CommitPolicy::Any if results.iter().all(failed) => Some(false),
+            CommitPolicy::Any => None,
+            _ => {
+                let mut required = results.iter().enumerate().filter(|(index, _)| self.required(*index));
+                if required.clone().any(|(_, it)| failed(it)) {
+                    Some(false)
+                } else if required.all(|(_, it)| written(it)) {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SendTrait for FanOut {
+    async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+        let deadline = Instant::now() + self.best_effort;
+        let mut pending: FuturesUnordered<_> = self.sinks.iter().enumerate()
+            .map(|(index, (_, sink, _))| {
+                let batch = message.clone();
+                async move { (index, sink.push(batch).await) }
+            })
+            .collect();
+        let mut results: Vec<Option<Result<(), SyncError>>> = self.sinks.iter().map(|_| None).collect();
+        let decision = loop {
+            let decision = self.decided(&results);
+            let next = match decision {
+                // A required sink failed, the batch is retried on every sink anyway.
+                Some(false) => break false,
+                // The remaining sinks are best effort.
+                Some(true) => match timeout_at(deadline, pending.next()).await {
+                    Ok(next) => next,
+                    Err(_) => break true,
+                },
+                None => pending.next().await,
+            };
+            match next {
+                Some((index, result)) => results[index] = Some(result),
+                None => break decision.unwrap_or(true),
+            }
+        };
+        let mut failure = None;
+        for (index, ((name, _, _), result)) in self.sinks.iter().zip(results).enumerate() {
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) if !decision && failure.is_none() && self.required(index) => failure = Some(e),
+                Some(Err(e)) => warn!("[FanOut] {} could not write the batch: {}", name, e),
+                None if decision => warn!("[FanOut] {} skips the batch, not written within {:?}", name, self.best_effort),
+                None => {}
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Open while a sink the policy waits for is unavailable, under `any` while every sink is.
+    /// The circuits of the best-effort sinks are left out: their batches are skipped rather
+    /// than retried, so the source is not paused for them.
+    fn circuit(&self) -> Option<watch::Receiver<bool>> {
+        let mut circuits = Vec::new();
+        for (index, (_, sink, _)) in self.sinks.iter().enumerate() {
+            match sink.circuit() {
+                Some(circuit) if self.required(index) => circuits.push(circuit),
+                Some(_) => {}
+                // A sink that is always available satisfies `any` on its own.
+                None if self.commit == CommitPolicy::Any => return None,
+                None => {}
+            }
+        }
+        if circuits.is_empty() {
+            return None;
+        }
+        let commit = self.commit;
+        let open = move |circuits: &[watch::Receiver<bool>]| match commit {
+            CommitPolicy::Any => circuits.iter().all(|it| *it.borrow()),
+            _ => circuits.iter().any(|it| *it.borrow()),
+        };
+        let (sender, receiver) = watch::channel(open(&circuits));
+        tokio::spawn(async move {
+            loop {
+                let changes = circuits.iter_mut().map(|it| Box::pin(it.changed()));
+                tokio::select! {
+                    (changed, _, _) = select_all(changes) => if changed.is_err() {
+                        return;
+                    },
+                    _ = sender.closed() => return,
+                }
+                let open = open(&circuits);
+                sender.send_if_modified(|it| std::mem::replace(it, open) != open);
+            }
+        });
+        Some(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Sink {
+        Written,
+        Failed,
+        /// E.g. a Clickhouse sink retrying a server that is down.
+        NeverReady,
+    }
+
+    #[async_trait]
+    impl SendTrait for Sink {
+        async fn push(&self, _: Vec<LogMessage>) -> Result<(), SyncError> {
+            match self {
+                Sink::Written => Ok(()),
+                Sink::Failed => Err(SyncError::Option),
+                Sink::NeverReady => std::future::pending().await,
+            }
+        }
+    }
+
+    fn fan_out(commit: CommitPolicy, sinks: Vec<(Sink, bool)>) -> FanOut {
+        let mut fan_out = FanOut::new(commit, Duration::from_millis(20));
+        for (index, (sink, primary)) in sinks.into_iter().enumerate() {
+            fan_out.add(&format!("sink-{}", index), Arc::new(sink), primary);
+        }
+        fan_out
+    }
+
+    fn batch() -> Vec<LogMessage> {
+        vec![LogMessage::new("logs", 0, 0, "")]
+    }
+
+    #[tokio::test]
+    async fn commit_policies() {
+        // (sink, primary) of every sink.
+        let sinks = || vec![(Sink::Written, true), (Sink::Failed, false)];
+        assert!(fan_out(CommitPolicy::All, sinks()).push(batch()).await.is_err());
+        assert!(fan_out(CommitPolicy::Any, sinks()).push(batch()).await.is_ok());
+        assert!(fan_out(CommitPolicy::Primary, sinks()).push(batch()).await.is_ok());
+        assert!(fan_out(CommitPolicy::Primary, vec![(Sink::Failed, true), (Sink::Written, false)]).push(batch()).await.is_err());
+        assert!(fan_out(CommitPolicy::Any, vec![(Sink::Failed, true), (Sink::Failed, false)]).push(batch()).await.is_err());
+    }
+
+    /// A sink retrying behind a circuit, like the Clickhouse ones.
+    struct Breaker(watch::Receiver<bool>);
+
+    #[async_trait]
+    impl SendTrait for Breaker {
+        async fn push(&self, _: Vec<LogMessage>) -> Result<(), SyncError> {
+            Ok(())
+        }
+
+        fn circuit(&self) -> Option<watch::Receiver<bool>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn circuit_of_the_required_sinks() -> Result<(), SyncError> {
+        let (sender, sender_circuit) = watch::channel(false);
+        let (output, output_circuit) = watch::channel(false);
+        let circuit = |commit| {
+            let mut fan_out = FanOut::new(commit, Duration::from_millis(20));
+            fan_out.add("sender", Arc::new(Breaker(sender_circuit.clone())), true);
+            fan_out.add("outputs[0]", Arc::new(Breaker(output_circuit.clone())), false);
+            fan_out.circuit().ok_or(SyncError::Option)
+        };
+        let wait = |circuit: &watch::Receiver<bool>, open: bool| {
+            let mut circuit = circuit.clone();
+            async move { tokio::time::timeout(Duration::from_secs(1), circuit.wait_for(|it| *it == open)).await.is_ok() }
+        };
+        let all = circuit(CommitPolicy::All)?;
+        let primary = circuit(CommitPolicy::Primary)?;
+        let any = circuit(CommitPolicy::Any)?;
+        // An output failing opens the circuit of the pipeline only when it is required.
+        output.send_replace(true);
+        assert!(wait(&all, true).await);
+        assert!(!*primary.borrow());
+        assert!(!*any.borrow());
+        sender.send_replace(true);
+        assert!(wait(&primary, true).await);
+        assert!(wait(&any, true).await);
+        output.send_replace(false);
+        assert!(wait(&any, false).await);
+        assert!(*all.borrow());
+
+        // `any` is satisfied by a sink without a circuit.
+        let mut fan_out = FanOut::new(CommitPolicy::Any, Duration::from_millis(20));
+        fan_out.add("sender", Arc::new(Breaker(sender_circuit.clone())), true);
+        fan_out.add("outputs[0]", Arc::new(Sink::Written), false);
+        assert!(fan_out.circuit().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn best_effort_sinks_do_not_hold_the_batch() {
+        let within = |fan_out: FanOut| async move {
+            tokio::time::timeout(Duration::from_secs(1), fan_out.push(batch())).await
+        };
+        let primary = fan_out(CommitPolicy::Primary, vec![(Sink::Written, true), (Sink::NeverReady, false)]);
+        assert!(matches!(within(primary).await, Ok(Ok(()))));
+        let any = fan_out(CommitPolicy::Any, vec![(Sink::NeverReady, true), (Sink::Written, false)]);
+        assert!(matches!(within(any).await, Ok(Ok(()))));
+        // A required sink holds the batch back until it is written.
+        let all = fan_out(CommitPolicy::All, vec![(Sink::Written, true), (Sink::NeverReady, false)]);
+        assert!(within(all).await.is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::metrics::pipeline;
+
+static PIPELINES: LazyLock<Mutex<HashMap<String, Arc<Health>>>> = LazyLock::new(Default::default);
+
+/// Health of a pipeline, the same one every time it is restarted.
+pub fn of(name: &str) -> Arc<Health> {
+    let mut pipelines = PIPELINES.lock().unwrap_or_else(|e| e.into_inner());
+    pipelines.entry(name.to_owned()).or_default().clone()
+}
+
+/// Health of the pipeline of the current task.
+pub fn current() -> Arc<Health> {
+    of(&pipeline())
+}
+
+/// What the probes report of a pipeline, fed by its source, its sinks and its stages.
+pub struct Health {
+    started: Instant,
+    /// Milliseconds after `started` the last batch was pulled or delivered.
+    progress: AtomicU64,
+    /// Batches pulled and not delivered yet.
+    in_flight: AtomicUsize,
+    /// Whether the source is paused while the sink is unavailable.
+    paused: AtomicBool,
+    /// Whether the consumer group has assigned partitions to the source.
+    assigned: AtomicBool,
+    /// Clickhouse sink, named after its server and table => whether its last insert or query
+    /// succeeded. Several sinks of a pipeline may share a server.
+    sinks: Mutex<HashMap<String, bool>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            started: Instant::now(),
+            progress: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+            assigned: AtomicBool::new(false),
+            sinks: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Health {
+    fn progressed(&self) {
+        self.progress.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// A new run of the pipeline, nothing is in flight.
+    pub fn restarted(&self) {
+        self.in_flight.store(0, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    pub fn pulled(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    pub fn delivered(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    pub fn paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    pub fn assigned(&self, assigned: bool) {
+        self.assigned.store(assigned, Ordering::Relaxed);
+    }
+
+    pub fn sink(&self, name: &str, available: bool) {
+        let mut sinks = self.sinks.lock().unwrap_or_else(|e| e.into_inner());
+        sinks.insert(name.to_owned(), available);
+    }
+
+    /// Why the pipeline is stuck: batches are in flight but none moved for `timeout`, while
+    /// the sink is not known to be unavailable. Nothing when it is live.
+    pub fn stalled(&self, timeout: Duration) -> Option<String> {
+        if self.in_flight.load(Ordering::Relaxed) == 0 || self.paused.load(Ordering::Relaxed) {
+            return None;
+        }
+        let idle = self.started.elapsed().saturating_sub(Duration::from_millis(self.progress.load(Ordering::Relaxed)));
+        (idle > timeout).then(|| format!("no batch moved for {:?}", idle))
+    }
+
+    /// Why the pipeline cannot process records, nothing when it is ready.
+    pub fn unready(&self) -> Option<String> {
+        if !self.assigned.load(Ordering::Relaxed) {
+            return Some(String::from("no partition assigned"));
+        }
+        let sinks = self.sinks.lock().unwrap_or_else(|e| e.into_inner());
+        let mut unavailable: Vec<&str> = sinks.iter().filter(|(_, available)| !**available).map(|(name, _)| name.as_str()).collect();
+        unavailable.sort();
+        match (sinks.is_empty(), unavailable.as_slice()) {
+            (true, _) => Some(String::from("Clickhouse not reached yet")),
+            (false, []) => None,
+            (false, names) => Some(format!("Clickhouse unavailable: {}", names.join(", "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes() {
+        let health = Health::default();
+        assert!(health.stalled(Duration::ZERO).is_none());
+        assert_eq!(health.unready().as_deref(), Some("no partition assigned"));
+        health.assigned(true);
+        assert_eq!(health.unready().as_deref(), Some("Clickhouse not reached yet"));
+        health.sink("logs.app@http://127.0.0.1:8123", false);
+        assert_eq!(health.unready().as_deref(), Some("Clickhouse unavailable: logs.app@http://127.0.0.1:8123"));
+        // Another sink of the same server does not hide it.
+        health.sink("logs.archive@http://127.0.0.1:8123", true);
+        assert_eq!(health.unready().as_deref(), Some("Clickhouse unavailable: logs.app@http://127.0.0.1:8123"));
+        health.sink("logs.app@http://127.0.0.1:8123", true);
+        assert!(health.unready().is_none());
+
+        health.pulled();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(health.stalled(Duration::from_secs(60)).is_none());
+        assert!(health.stalled(Duration::from_millis(1)).is_some());
+        // Waiting for the sink to recover is expected.
+        health.paused(true);
+        assert!(health.stalled(Duration::from_millis(1)).is_none());
+        health.paused(false);
+        health.delivered();
+        assert!(health.stalled(Duration::ZERO).is_none());
+    }
+}
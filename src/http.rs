@@ -0,0 +1,110 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use log::info;
+
+use crate::config::HttpConfig;
+use crate::error::SyncError;
+use crate::health;
+use crate::metrics::METRICS;
+
+/// Pipelines reported by the probes.
+struct Probes {
+    pipelines: Vec<String>,
+    progress_timeout: Duration,
+}
+
+impl Probes {
+    /// 200 when `check` finds nothing wrong with any pipeline, 503 otherwise, with the state of
+    /// every pipeline.
+    fn report(&self, check: impl Fn(&str) -> Option<String>) -> Result<Response<Body>, hyper::http::Error> {
+        let mut healthy = true;
+        let mut text = String::new();
+        for name in &self.pipelines {
+            let problem = check(name);
+            healthy &= problem.is_none();
+            text.push_str(&format!("{}: {}\n", name, problem.as_deref().unwrap_or("ok")));
+        }
+        let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        Response::builder().status(status).body(Body::from(text))
+    }
+}
+
+/// Binds `http.listen` right away, so that a taken port fails the start, and returns the
+/// server to run. The probes report `pipelines`.
+pub fn bind(conf: &HttpConfig, pipelines: Vec<String>) -> Result<impl Future<Output=Result<(), SyncError>>, SyncError> {
+    let address: SocketAddr = conf.listen.parse()
+        .map_err(|e| SyncError::OptionParams(format!("'http.listen' {} is not an address: {}", conf.listen, e)))?;
+    let server = Server::try_bind(&address)?;
+    info!("[Http] Metrics are served on http://{}/metrics, probes on /healthz and /readyz", address);
+    let probes = Arc::new(Probes { pipelines, progress_timeout: Duration::from_millis(conf.progress_timeout) });
+    let service = make_service_fn(move |_| {
+        let probes = probes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(request, probes.clone())))
+        }
+    });
+    Ok(async move {
+        server.serve(service).await?;
+        Ok(())
+    })
+}
+
+async fn handle(request: Request<Body>, probes: Arc<Probes>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => probes.report(|name| health::of(name).stalled(probes.progress_timeout)),
+        (&Method::GET, "/readyz") => probes.report(|name| health::of(name).unready()),
+        (&Method::GET, "/metrics") => match METRICS.render() {
+            Ok(text) => Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(text)),
+            Err(e) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())),
+        },
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+    Ok(response.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(path: &str) -> Result<(StatusCode, String), SyncError> {
+        let probes = Arc::new(Probes {
+            pipelines: vec![String::from("http-test")],
+            progress_timeout: Duration::from_secs(60),
+        });
+        let request = Request::get(path).body(Body::empty()).map_err(|e| SyncError::OptionParams(e.to_string()))?;
+        let response = handle(request, probes).await.unwrap_or_default();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() -> Result<(), SyncError> {
+        METRICS.retries.with_label_values(&["test", "Clickhouse"]).inc();
+        let (status, body) = get("/metrics").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#"log2click_retries_total{operation="Clickhouse",pipeline="test"}"#), "{}", body);
+        assert_eq!(get("/other").await?.0, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_probes() -> Result<(), SyncError> {
+        let health = health::of("http-test");
+        assert_eq!(get("/healthz").await?, (StatusCode::OK, String::from("http-test: ok\n")));
+        assert_eq!(get("/readyz").await?, (StatusCode::SERVICE_UNAVAILABLE, String::from("http-test: no partition assigned\n")));
+        health.assigned(true);
+        health.sink("http://127.0.0.1:8123", true);
+        assert_eq!(get("/readyz").await?, (StatusCode::OK, String::from("http-test: ok\n")));
+        Ok(())
+    }
+}
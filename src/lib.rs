@@ -1,27 +1,39 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use derive_builder::Builder;
-use serde::Deserialize;
+use log::{info, warn};
 use serde_json::{Map, Value};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
 
 use crate::error::SyncError;
+use crate::health::Health;
+use crate::policy::DeadLetter;
 
 pub mod source;
 pub mod sink;
 pub mod parser;
 pub mod error;
+pub mod config;
+pub mod policy;
+pub mod dead_letter;
+pub mod retry;
+pub mod schema;
+pub mod rowbinary;
+pub mod ddl;
+pub mod archive;
+pub mod fanout;
+pub mod metrics;
+pub mod http;
+pub mod health;
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
-pub enum CObject {
-    Null,
-    Bool(bool),
-    Number(f64),
-    String(String),
-    Array(Vec<CObject>),
-    Object(HashMap<String, CObject>),
+tokio::task_local! {
+    /// Name of the pipeline a task belongs to, prefixed to its log lines.
+    pub static PIPELINE: String;
 }
 
 #[derive(Debug, Clone)]
@@ -32,69 +44,176 @@ pub struct LogMessage {
     pub offset: i64,
     pub log: Option<String>,
     pub map: Option<Map<String, Value>>,
+    /// Key of the Kafka message, as text.
+    pub key: Option<String>,
+    /// Milliseconds since the epoch, set by the producer or by the broker.
+    pub timestamp: Option<i64>,
+    /// Headers of the Kafka message in their order, their values as text.
+    pub headers: Vec<(String, String)>,
 }
 
-impl From<&CObject> for Option<HashMap<String, CObject>> {
-    fn from(obj: &CObject) -> Self {
-        match obj {
-            CObject::Object(map) => Some(map.clone()),
-            _ => None
+impl LogMessage {
+    /// A message not parsed yet, without key, timestamp nor headers.
+    pub fn new(topic: impl Into<String>, partition: i32, offset: i64, body: impl Into<String>) -> LogMessage {
+        LogMessage {
+            topic: topic.into(),
+            body: body.into(),
+            partition,
+            offset,
+            log: None,
+            map: None,
+            key: None,
+            timestamp: None,
+            headers: Vec::new(),
         }
     }
-}
 
-impl From<&CObject> for Vec<String> {
-    fn from(obj: &CObject) -> Self {
-        match obj {
-            CObject::Array(array) => {
-                array.to_vec().iter().map(|x| {
-                    match x {
-                        CObject::String(val) => val.to_owned(),
-                        _ => String::default()
-                    }
-                }).filter(|x| x != "").collect()
-            }
-            _ => Vec::default()
+    /// Prefix of the reserved fields describing the Kafka message: `_kafka.topic`,
+    /// `_kafka.partition`, `_kafka.offset`, `_kafka.timestamp`, `_kafka.key`, `_kafka.headers`
+    /// and `_kafka.headers.<name>`.
+    pub const METADATA: &'static str = "_kafka.";
+
+    /// Whether a field describes the Kafka message rather than being parsed from it.
+    pub fn is_metadata(field: &str) -> bool {
+        match field.strip_prefix(LogMessage::METADATA) {
+            Some("topic" | "partition" | "offset" | "timestamp" | "key" | "headers") => true,
+            Some(name) => name.strip_prefix("headers.").is_some_and(|it| !it.is_empty()),
+            None => false,
         }
     }
+
+    /// A parsed field, or a metadata field of the Kafka message. The timestamp is given in
+    /// RFC 3339, so that it converts to date and time columns as well as to text.
+    pub fn field(&self, name: &str) -> Option<Cow<'_, Value>> {
+        let Some(metadata) = name.strip_prefix(LogMessage::METADATA) else {
+            return self.map.as_ref()?.get(name).map(Cow::Borrowed);
+        };
+        let value = match metadata {
+            "topic" => Value::String(self.topic.to_owned()),
+            "partition" => Value::from(self.partition),
+            "offset" => Value::from(self.offset),
+            "timestamp" => {
+                let time = chrono::DateTime::from_timestamp_millis(self.timestamp?)?;
+                Value::String(time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+            }
+            "key" => Value::String(self.key.clone()?),
+            "headers" => Value::Object(self.headers.iter().map(|(name, value)| (name.to_owned(), Value::String(value.to_owned()))).collect()),
+            _ => {
+                let header = metadata.strip_prefix("headers.")?;
+                let (_, value) = self.headers.iter().find(|(name, _)| name == header)?;
+                Value::String(value.to_owned())
+            }
+        };
+        Some(Cow::Owned(value))
+    }
 }
 
-impl From<&CObject> for String {
-    fn from(obj: &CObject) -> Self {
-        match obj {
-            CObject::String(v) => v.to_owned(),
-            _ => String::default()
-        }
+/// Highest offset per topic and partition of a batch, i.e. the progress to commit once the
+/// batch has been handled.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Offsets(BTreeMap<(String, i32), i64>);
+
+impl Offsets {
+    pub fn track(&mut self, message: &LogMessage) {
+        self.insert(&message.topic, message.partition, message.offset);
+    }
+
+    /// Keeps the highest offset of the partition.
+    pub fn insert(&mut self, topic: &str, partition: i32, offset: i64) {
+        let highest = self.0.entry((topic.to_owned(), partition)).or_insert(offset);
+        *highest = (*highest).max(offset);
+    }
+
+    pub fn remove(&mut self, topic: &str, partition: i32) {
+        self.0.remove(&(topic.to_owned(), partition));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.0.get(&(topic.to_owned(), partition)).copied()
+    }
+
+    /// `(topic, partition, offset)` of the last message handled in every partition.
+    pub fn iter(&self) -> impl Iterator<Item=(&str, i32, i64)> {
+        self.0.iter().map(|((topic, partition), offset)| (topic.as_str(), *partition, *offset))
     }
 }
 
-impl From<&CObject> for f64 {
-    fn from(obj: &CObject) -> Self {
-        match obj {
-            CObject::Number(v) => *v,
-            _ => 0f64
+impl From<&[LogMessage]> for Offsets {
+    fn from(messages: &[LogMessage]) -> Self {
+        let mut offsets = Offsets::default();
+        for x in messages {
+            offsets.track(x);
         }
+        offsets
     }
 }
 
-#[async_trait(? Send)]
-pub trait Filter {
+#[async_trait]
+pub trait Filter: Send + Sync {
     async fn process(&self, data: Vec<LogMessage>) -> Result<Vec<LogMessage>, SyncError>;
 }
 
-#[async_trait(? Send)]
-pub trait ReceiveTrait {
+#[async_trait]
+pub trait ReceiveTrait: Send + Sync {
+    /// Next batch of messages; an empty batch means the source has been stopped and drained.
     async fn pull(&self) -> Result<Vec<LogMessage>, SyncError>;
 
-    async fn confirm(&self) -> Result<(), SyncError>;
+    /// Commits the offsets of a batch once every message of it has been handled.
+    async fn confirm(&self, offsets: &Offsets) -> Result<(), SyncError>;
+
+    /// Stops receiving new messages, `pull` still returns those already received.
+    fn stop(&self) {}
+
+    /// Stops fetching while the sink is unavailable, `pull` is called until a batch is held back.
+    fn pause(&self) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Releases the source once the last batch has been confirmed.
+    async fn close(&self) -> Result<(), SyncError> {
+        Ok(())
+    }
 }
 
-#[async_trait(? Send)]
-pub trait SendTrait {
+#[async_trait]
+pub trait SendTrait: Send + Sync {
     async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError>;
+
+    /// `true` while the sink is unavailable and the source is better paused, none for the
+    /// sinks that do not retry.
+    fn circuit(&self) -> Option<watch::Receiver<bool>> {
+        None
+    }
 }
 
 
+/// Target of the records rejected with the `dead-letter` error policy.
+#[async_trait]
+pub trait DeadLetterTrait: Send + Sync {
+    async fn send(&self, letter: DeadLetter) -> Result<(), SyncError>;
+}
+
+/// Durable record of the offsets a sink has written, consulted by the source when partitions
+/// are assigned.
+#[async_trait]
+pub trait OffsetStore: Send + Sync {
+    async fn load(&self) -> Result<Offsets, SyncError>;
+
+    /// Last offset per partition of the batches the sink started writing, ahead of the loaded
+    /// offsets when it stopped before recording them.
+    async fn intents(&self) -> Result<Offsets, SyncError> {
+        Ok(Offsets::default())
+    }
+}
+
 #[derive(Default, Builder)]
 #[builder(setter(into))]
 pub struct Pip {
@@ -102,20 +221,418 @@ pub struct Pip {
     filters: Option<Vec<Arc<dyn Filter>>>,
     source: Option<Arc<dyn ReceiveTrait>>,
     sink: Option<Arc<dyn SendTrait>>,
+    /// Set to `true` to finish the batches already received and stop.
+    #[builder(default = "None")]
+    shutdown: Option<watch::Receiver<bool>>,
+    /// Circuit of the sink, `true` while it is unavailable.
+    #[builder(default = "None")]
+    circuit: Option<watch::Receiver<bool>>,
+    /// Batches queued between two stages.
+    #[builder(default = "2")]
+    queue_size: usize,
+    /// Told about the progress of the batches, for the probes.
+    #[builder(default = "None")]
+    health: Option<Arc<Health>>,
+}
+
+/// A batch and the offsets to commit once it has been delivered.
+type Batch = (Offsets, Vec<LogMessage>);
+
+/// Completes once `flag` is `true`, never without a flag.
+async fn raised(flag: &mut Option<watch::Receiver<bool>>) {
+    if let Some(flag) = flag {
+        if flag.wait_for(|raised| *raised).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Next value of `flag`, never without a flag.
+async fn changed(flag: &mut Option<watch::Receiver<bool>>) -> bool {
+    if let Some(flag) = flag {
+        if flag.changed().await.is_ok() {
+            return *flag.borrow_and_update();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Spawns a stage in the pipeline of the current task, if any.
+fn spawn<F>(stages: &mut JoinSet<Result<(), SyncError>>, stage: F)
+where
+    F: Future<Output=Result<(), SyncError>> + Send + 'static,
+{
+    match PIPELINE.try_with(|name| name.to_owned()) {
+        Ok(name) => stages.spawn(PIPELINE.scope(name, stage)),
+        Err(_) => stages.spawn(stage),
+    };
+}
+
+/// Pulls batches until the source is stopped and drained. While the sink is unavailable the
+/// source is paused, and no longer pulled once a batch is held back, so that an outage does not
+/// buffer the topic in memory.
+async fn receive_batches(
+    source: Arc<dyn ReceiveTrait>,
+    batches: mpsc::Sender<Batch>,
+    mut shutdown: Option<watch::Receiver<bool>>,
+    mut circuit: Option<watch::Receiver<bool>>,
+    health: Arc<Health>,
+) -> Result<(), SyncError> {
+    let mut pending = VecDeque::new();
+    let (mut stopping, mut paused, mut drained) = (false, false, false);
+    // `changed` only reports the next transition, a circuit open from the start is handled here.
+    if circuit.as_mut().is_some_and(|it| *it.borrow_and_update()) {
+        warn!("Sink unavailable, pausing the source until it recovers ...");
+        source.pause()?;
+        paused = true;
+        health.paused(paused);
+    }
+    // Only dropped once it has completed, pulled messages are never lost.
+    let mut pull = source.pull();
+    while !drained || !pending.is_empty() {
+        tokio::select! {
+            biased;
+            _ = raised(&mut shutdown), if !stopping => {
+                info!("Stopping, the messages already received are still delivered ...");
+                source.stop();
+                stopping = true;
+            }
+            open = changed(&mut circuit) => {
+                if open && !paused {
+                    warn!("Sink unavailable, pausing the source until it recovers ...");
+                    source.pause()?;
+                } else if !open && paused {
+                    source.resume()?;
+                    info!("Sink available again, the source resumed");
+                }
+                paused = open;
+                health.paused(paused);
+            }
+            _ = batches.closed() => return Ok(()),
+            permit = batches.reserve(), if !pending.is_empty() => {
+                let Ok(permit) = permit else {
+                    return Ok(());
+                };
+                if let Some(batch) = pending.pop_front() {
+                    permit.send(batch);
+                }
+            }
+            batch = &mut pull, if !drained && pending.is_empty() => {
+                let batch = batch?;
+                drained = batch.is_empty();
+                if !drained {
+                    health.pulled();
+                    pending.push_back((Offsets::from(batch.as_slice()), batch));
+                    pull = source.pull();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn filter_batches(filters: Vec<Arc<dyn Filter>>, mut received: mpsc::Receiver<Batch>, filtered: mpsc::Sender<Batch>) -> Result<(), SyncError> {
+    while let Some((offsets, mut messasge)) = received.recv().await {
+        for x in &filters {
+            messasge = x.process(messasge).await?;
+        }
+        if filtered.send((offsets, messasge)).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the batches in the order they were pulled, and commits each one once pushed.
+async fn deliver_batches(
+    source: Arc<dyn ReceiveTrait>,
+    sink: Arc<dyn SendTrait>,
+    mut filtered: mpsc::Receiver<Batch>,
+    health: Arc<Health>,
+) -> Result<(), SyncError> {
+    while let Some((offsets, messasge)) = filtered.recv().await {
+        sink.push(messasge).await?;
+        source.confirm(&offsets).await?;
+        health.delivered();
+    }
+    Ok(())
 }
 
 impl Pip {
+    /// Runs the source, the filters and the sink as stages connected by bounded queues, so
+    /// that the next batches are pulled and filtered while one is pushed.
     pub async fn run(self) -> Result<(), SyncError> {
         let receive = self.source.ok_or(SyncError::Option)?;
         let send = self.sink.ok_or(SyncError::Option)?;
-        let filters = self.filters.unwrap_or(Vec::default());
-        loop {
-            let mut messasge = receive.pull().await?;
-            for x in &filters {
-                messasge = x.process(messasge).await?;
+        let filters = self.filters.unwrap_or_default();
+        let health = self.health.unwrap_or_default();
+        health.restarted();
+        let (batches, received) = mpsc::channel(self.queue_size.max(1));
+        let (filtered, to_deliver) = mpsc::channel(self.queue_size.max(1));
+        let mut stages = JoinSet::new();
+        spawn(&mut stages, receive_batches(receive.clone(), batches, self.shutdown, self.circuit, health.clone()));
+        spawn(&mut stages, filter_batches(filters, received, filtered));
+        spawn(&mut stages, deliver_batches(receive.clone(), send, to_deliver, health));
+        // The first failure stops the other stages, dropping the set aborts them.
+        while let Some(stage) = stages.join_next().await {
+            match stage {
+                Ok(result) => result?,
+                Err(e) => return Err(SyncError::OptionParams(format!("Pipeline stage ended abnormally: {}", e))),
+            }
+        }
+        receive.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn message(partition: i32, offset: i64) -> LogMessage {
+        LogMessage::new("logs", partition, offset, "")
+    }
+
+    struct Memory {
+        batches: Mutex<VecDeque<Vec<LogMessage>>>,
+        committed: Mutex<Vec<(String, i32, i64)>>,
+        stopped: AtomicBool,
+        paused: Mutex<Vec<bool>>,
+        closed: AtomicBool,
+    }
+
+    impl Memory {
+        fn new(batches: Vec<Vec<LogMessage>>) -> Memory {
+            Memory {
+                batches: Mutex::new(VecDeque::from(batches)),
+                committed: Mutex::new(Vec::new()),
+                stopped: AtomicBool::new(false),
+                paused: Mutex::new(Vec::new()),
+                closed: AtomicBool::new(false),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ReceiveTrait for Memory {
+        async fn pull(&self) -> Result<Vec<LogMessage>, SyncError> {
+            let batch = self.batches.lock().unwrap().pop_front();
+            match batch {
+                Some(batch) => Ok(batch),
+                None if self.stopped.load(Ordering::SeqCst) => Ok(Vec::new()),
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn confirm(&self, offsets: &Offsets) -> Result<(), SyncError> {
+            let mut committed = self.committed.lock().unwrap();
+            committed.extend(offsets.iter().map(|(topic, partition, offset)| (topic.to_owned(), partition, offset)));
+            Ok(())
+        }
+
+        fn stop(&self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        fn pause(&self) -> Result<(), SyncError> {
+            self.paused.lock().unwrap().push(true);
+            Ok(())
+        }
+
+        fn resume(&self) -> Result<(), SyncError> {
+            self.paused.lock().unwrap().push(false);
+            Ok(())
+        }
+
+        async fn close(&self) -> Result<(), SyncError> {
+            self.closed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    struct FailOn(i64);
+
+    #[async_trait]
+    impl SendTrait for FailOn {
+        async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+            match message.iter().any(|it| it.offset == self.0) {
+                true => Err(SyncError::Option),
+                false => Ok(()),
+            }
+        }
+    }
+
+    /// Delivers its first batch once at most `until` batches are left in the source.
+    struct Slow {
+        source: Arc<Memory>,
+        until: usize,
+        pushed: Mutex<Vec<i64>>,
+    }
+
+    impl Slow {
+        fn new(source: &Arc<Memory>, until: usize) -> Arc<Slow> {
+            Arc::new(Slow { source: source.clone(), until, pushed: Mutex::new(Vec::new()) })
+        }
+    }
+
+    #[async_trait]
+    impl SendTrait for Slow {
+        async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+            if self.pushed.lock().unwrap().is_empty() {
+                while self.source.batches.lock().unwrap().len() > self.until {
+                    tokio::task::yield_now().await;
+                }
+            }
+            self.pushed.lock().unwrap().extend(message.iter().map(|it| it.offset));
+            Ok(())
+        }
+    }
+
+    /// Keeps its circuit open for a while on its first batch, and counts the batches left in the
+    /// source meanwhile.
+    struct Unavailable {
+        circuit: watch::Sender<bool>,
+        source: Arc<Memory>,
+        left: Mutex<Option<usize>>,
+        pushed: Mutex<Vec<i64>>,
+    }
+
+    #[async_trait]
+    impl SendTrait for Unavailable {
+        async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+            if self.pushed.lock().unwrap().is_empty() {
+                self.circuit.send_replace(true);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                *self.left.lock().unwrap() = Some(self.source.batches.lock().unwrap().len());
+                self.circuit.send_replace(false);
             }
-            send.push(messasge).await?;
-            receive.confirm().await?;
+            self.pushed.lock().unwrap().extend(message.iter().map(|it| it.offset));
+            Ok(())
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn offsets_keep_highest_per_partition() {
+        let mut offsets = Offsets::from([message(1, 7), message(0, 3), message(1, 5)].as_slice());
+        assert_eq!(offsets.iter().collect::<Vec<_>>(), vec![("logs", 0, 3), ("logs", 1, 7)]);
+        offsets.insert("logs", 1, 6);
+        offsets.insert("audit", 0, 2);
+        assert_eq!(offsets.iter().collect::<Vec<_>>(), vec![("audit", 0, 2), ("logs", 0, 3), ("logs", 1, 7)]);
+    }
+
+    #[test]
+    fn metadata_fields() {
+        let message = LogMessage {
+            key: Some(String::from("order-42")),
+            timestamp: Some(1714558830123),
+            headers: vec![(String::from("trace-id"), String::from("7f3a"))],
+            map: serde_json::json!({"level": "INFO"}).as_object().cloned(),
+            ..message(2, 9)
+        };
+        let field = |name: &str| message.field(name).map(|it| it.into_owned());
+        assert_eq!(field("level"), Some(Value::from("INFO")));
+        assert_eq!(field("_kafka.topic"), Some(Value::from("logs")));
+        assert_eq!(field("_kafka.partition"), Some(Value::from(2)));
+        assert_eq!(field("_kafka.offset"), Some(Value::from(9)));
+        assert_eq!(field("_kafka.timestamp"), Some(Value::from("2024-05-01T10:20:30.123Z")));
+        assert_eq!(field("_kafka.key"), Some(Value::from("order-42")));
+        assert_eq!(field("_kafka.headers.trace-id"), Some(Value::from("7f3a")));
+        assert_eq!(field("_kafka.headers"), Some(serde_json::json!({"trace-id": "7f3a"})));
+        assert_eq!(field("_kafka.headers.other"), None);
+        assert!(LogMessage::is_metadata("_kafka.headers.trace-id"));
+        assert!(!LogMessage::is_metadata("_kafka.headers.") && !LogMessage::is_metadata("_kafka.other"));
+    }
+
+    #[tokio::test]
+    async fn only_pushed_batches_are_committed() {
+        let source = Arc::new(Memory::new(vec![
+            vec![message(0, 0), message(1, 0), message(0, 1)],
+            vec![message(0, 2), message(1, 1)],
+        ]));
+        let result = PipBuilder::default()
+            .source(Some(source.clone() as Arc<dyn ReceiveTrait>))
+            .sink(Some(Arc::new(FailOn(2)) as Arc<dyn SendTrait>))
+            .build().unwrap()
+            .run().await;
+        assert!(result.is_err());
+        assert_eq!(*source.committed.lock().unwrap(), vec![(String::from("logs"), 0, 1), (String::from("logs"), 1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_and_closes_the_source() -> Result<(), SyncError> {
+        let source = Arc::new(Memory::new(vec![vec![message(0, 0), message(0, 1)]]));
+        let (_stop, shutdown) = watch::channel(true);
+        PipBuilder::default()
+            .source(Some(source.clone() as Arc<dyn ReceiveTrait>))
+            .sink(Some(Arc::new(FailOn(-1)) as Arc<dyn SendTrait>))
+            .shutdown(Some(shutdown))
+            .build()?
+            .run().await?;
+        assert_eq!(*source.committed.lock().unwrap(), vec![(String::from("logs"), 0, 1)]);
+        assert!(source.closed.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_are_pulled_while_one_is_pushed() -> Result<(), SyncError> {
+        let source = Arc::new(Memory::new((0..6).map(|offset| vec![message(0, offset)]).collect()));
+        let sink = Slow::new(&source, 3);
+        let (_stop, shutdown) = watch::channel(true);
+        PipBuilder::default()
+            .source(Some(source.clone() as Arc<dyn ReceiveTrait>))
+            .sink(Some(sink.clone() as Arc<dyn SendTrait>))
+            .shutdown(Some(shutdown))
+            .queue_size(1usize)
+            .build()?
+            .run().await?;
+        assert_eq!(*sink.pushed.lock().unwrap(), (0..6).collect::<Vec<_>>());
+        let committed: Vec<i64> = source.committed.lock().unwrap().iter().map(|it| it.2).collect();
+        assert_eq!(committed, (0..6).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn source_is_paused_while_the_sink_is_unavailable() -> Result<(), SyncError> {
+        let source = Arc::new(Memory::new((0..20).map(|offset| vec![message(0, offset)]).collect()));
+        let (circuit, receiver) = watch::channel(false);
+        let sink = Arc::new(Unavailable { circuit, source: source.clone(), left: Mutex::new(None), pushed: Mutex::new(Vec::new()) });
+        let (_stop, shutdown) = watch::channel(true);
+        PipBuilder::default()
+            .source(Some(source.clone() as Arc<dyn ReceiveTrait>))
+            .sink(Some(sink.clone() as Arc<dyn SendTrait>))
+            .shutdown(Some(shutdown))
+            .circuit(Some(receiver))
+            .queue_size(1usize)
+            .build()?
+            .run().await?;
+        assert_eq!(*source.paused.lock().unwrap(), vec![true, false]);
+        // The batch pushed, one in each queue and in the filter, and the one held back.
+        let left = sink.left.lock().unwrap().unwrap_or_default();
+        assert!(left >= 15, "{} batches pulled while the circuit was open", 20 - left);
+        assert_eq!(*sink.pushed.lock().unwrap(), (0..20).collect::<Vec<_>>());
+        assert_eq!(source.committed.lock().unwrap().len(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn source_starts_paused_behind_an_open_circuit() -> Result<(), SyncError> {
+        let source = Arc::new(Memory::new((0..3).map(|offset| vec![message(0, offset)]).collect()));
+        // Open before the pipeline starts, and never changed afterwards.
+        let (_circuit, receiver) = watch::channel(true);
+        let (_stop, shutdown) = watch::channel(true);
+        PipBuilder::default()
+            .source(Some(source.clone() as Arc<dyn ReceiveTrait>))
+            .sink(Some(Arc::new(FailOn(-1)) as Arc<dyn SendTrait>))
+            .shutdown(Some(shutdown))
+            .circuit(Some(receiver))
+            .build()?
+            .run().await?;
+        assert_eq!(*source.paused.lock().unwrap(), vec![true]);
+        assert_eq!(source.committed.lock().unwrap().len(), 3);
+        Ok(())
+    }
+}
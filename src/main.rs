@@ -1,16 +1,30 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::Write;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use chrono::{ Local};
-use clap::Parser;
-use config::Config;
-use log::{error, info, Level};
+use chrono::Local;
+use clap::{Parser, Subcommand};
+use log::{error, info, warn, Level};
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 
-use log2click::{CObject, Filter, PipBuilder, ReceiveTrait, SendTrait};
+use log2click::{DeadLetterTrait, Filter, LogMessage, PIPELINE, PipBuilder, ReceiveTrait, SendTrait};
+use log2click::archive::Archive;
+use log2click::config::{DeadLetterConfig, Log2ClickConfig, OutputConfig, PipelineConfig, RetryConfig, StartFrom};
+use log2click::ddl::TableSchema;
+use log2click::dead_letter::{FileDeadLetter, KafkaDeadLetter, Replay};
 use log2click::error::SyncError;
+use log2click::fanout::FanOut;
+use log2click::health;
+use log2click::http;
 use log2click::parser::{Json, Regular};
+use log2click::policy::{LogDeadLetter, OnError, Stage};
+use log2click::retry::Retry;
+use log2click::schema::Cell;
 use log2click::sink::Clickhouse;
 use log2click::source::Kafka;
 
@@ -24,29 +38,355 @@ struct Args {
     /// Enable debugging
     #[arg(long, default_value = "false")]
     debug: bool,
+    /// Pipeline of the subcommands, needed when several are configured.
+    #[arg(short, long)]
+    pipeline: Option<String>,
+    /// Position of the partitions without committed offsets, over `receive.kafka.start-from`: earliest,
+    /// latest, an RFC 3339 time or `topic:partition=offset, ...`. Only the `--pipeline` one when given.
+    #[arg(long)]
+    start_from: Option<StartFrom>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn try_main(conf: HashMap<String, CObject>) -> Result<(), SyncError> {
-    let source: Arc<dyn ReceiveTrait> = Arc::new(Kafka::create(&conf)?);
-    let sink: Arc<dyn SendTrait> = Arc::new(Clickhouse::create(&conf)?);
-    let mut filters = Vec::new();
-    filters.push(Arc::new(Json) as Arc<dyn Filter>);
-    filters.push(Arc::new(Regular::create(&conf)?) as Arc<dyn Filter>);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the configuration without connecting to Kafka or Clickhouse.
+    Validate,
+    /// Run sample payloads through the configured filters and print the rows that would be inserted,
+    /// without connecting to Kafka or Clickhouse.
+    TestParse {
+        /// File with one payload per line, either NDJSON envelopes or plain log lines; `-` reads stdin.
+        #[arg(default_value = "-")]
+        input: String,
+        /// YAML file of the column types of the tables, `table: {column: type}`. Without it, columns
+        /// have the types `create-table` would create them with.
+        #[arg(long)]
+        schema: Option<String>,
+    },
+    /// Send the records of a dead-letter file through the pipeline again, e.g. once the configuration is fixed.
+    Replay {
+        /// Dead-letter NDJSON file.
+        input: String,
+    },
+    /// Print the CREATE TABLE statement of the table `sender.mapping` is written to.
+    GenerateDdl,
+}
+
+fn dead_letter(conf: &PipelineConfig) -> Result<Arc<dyn DeadLetterTrait>, SyncError> {
+    Ok(match &conf.dead_letter {
+        None => Arc::new(LogDeadLetter),
+        Some(DeadLetterConfig::Kafka { topic }) => Arc::new(KafkaDeadLetter::create(&conf.receive.kafka, topic)?),
+        Some(DeadLetterConfig::File { path, max_size, max_files }) => {
+            Arc::new(FileDeadLetter::create(path, *max_size, *max_files))
+        }
+    })
+}
+
+/// Filters and sinks of a pipeline.
+struct Stages {
+    filters: Vec<Arc<dyn Filter>>,
+    /// Every Clickhouse sink, the one of `sender` first.
+    clickhouse: Vec<Arc<Clickhouse>>,
+    /// The sink of `sender`, or the fan-out to it and the outputs.
+    sink: Arc<dyn SendTrait>,
+}
+
+impl Stages {
+    fn create(conf: &PipelineConfig) -> Result<Stages, SyncError> {
+        let dead_letter = dead_letter(conf)?;
+        let on_error = |stage, policy| OnError::new(stage, policy, dead_letter.clone());
+        let filters = vec![
+            Arc::new(Json::create(&conf.parser, on_error(Stage::Json, conf.on_error.json))) as Arc<dyn Filter>,
+            Arc::new(Regular::create(&conf.parser, on_error(Stage::Regex, conf.on_error.regex))?) as Arc<dyn Filter>,
+        ];
+        let sender = Arc::new(Clickhouse::create(&conf.sender, on_error(Stage::Sink, conf.on_error.sink))?);
+        let mut clickhouse = vec![sender.clone()];
+        if conf.outputs.is_empty() {
+            return Ok(Stages { filters, clickhouse, sink: sender });
+        }
+        let mut fan_out = FanOut::new(conf.commit, Duration::from_millis(conf.best_effort_timeout));
+        fan_out.add(&conf.sender.clickhouse.table, sender, true);
+        for (index, output) in conf.outputs.iter().enumerate() {
+            let sink: Arc<dyn SendTrait> = match output {
+                OutputConfig::Clickhouse { sender, .. } => {
+                    let sink = Arc::new(Clickhouse::create(sender, on_error(Stage::Sink, conf.on_error.sink))?);
+                    clickhouse.push(sink.clone());
+                    sink
+                }
+                OutputConfig::File { path, max_size, max_files, .. } => Arc::new(Archive::create(path, *max_size, *max_files)),
+            };
+            fan_out.add(&format!("outputs[{}]", index), sink, output.primary());
+        }
+        Ok(Stages { filters, clickhouse, sink: Arc::new(fan_out) })
+    }
+
+    /// Prepares the Clickhouse side of every sink.
+    async fn init(&self) -> Result<(), SyncError> {
+        for x in &self.clickhouse {
+            x.init().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a pipeline until a shutdown is requested or it fails.
+async fn run_pipeline(conf: PipelineConfig, shutdown: watch::Receiver<bool>) -> Result<(), SyncError> {
+    let stages = Stages::create(&conf)?;
+    stages.init().await?;
+    // Exactly-once delivery only concerns the sink of `sender`.
+    let sender = &stages.clickhouse[0];
+    let source: Arc<dyn ReceiveTrait> = Arc::new(Kafka::create(&conf.receive.kafka, sender.offset_store())?);
+    let circuit = stages.sink.circuit();
     PipBuilder::default()
         .source(Some(source))
-        .filters(filters)
-        .sink(Some(sink))
+        .filters(stages.filters)
+        .sink(Some(stages.sink))
+        .shutdown(Some(shutdown))
+        .circuit(circuit)
+        .queue_size(conf.queue_size)
+        .health(Some(health::of(&conf.name)))
         .build()?
         .run().await?;
+    info!("Pipeline stopped");
+    Ok(())
+}
 
-    return Ok(());
+/// Restarts a pipeline with backoff whenever it fails or panics, until a shutdown is requested.
+async fn supervise(conf: PipelineConfig, mut shutdown: watch::Receiver<bool>) {
+    let backoff = Retry::new(&RetryConfig { backoff: 1000, max_backoff: 60000, threshold: 1, max_attempts: None });
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        // In a task of its own, so that a panic only ends this run.
+        let run = PIPELINE.scope(conf.name.to_owned(), run_pipeline(conf.clone(), shutdown.clone()));
+        let error = match tokio::spawn(run).await {
+            Ok(Ok(())) => return,
+            Ok(Err(error)) => error,
+            Err(error) => SyncError::OptionParams(format!("Pipeline panicked: {}", error)),
+        };
+        report(&error);
+        if *shutdown.borrow() {
+            return;
+        }
+        // A pipeline that ran for a while starts over from the shortest delay.
+        failures = if started.elapsed() > Duration::from_secs(60) { 1 } else { failures + 1 };
+        let delay = backoff.delay(failures);
+        error!("Pipeline failed, restarting in {:?} ...", delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        }
+    }
+}
+
+async fn try_main(conf: Log2ClickConfig) -> Result<(), SyncError> {
+    if let Some(http) = &conf.http {
+        let server = http::bind(http, conf.pipelines.iter().map(|it| it.name.to_owned()).collect())?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("[Http] Server stopped: {}", e);
+            }
+        });
+    }
+    let (stop, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        let _ = stop.send(true);
+    });
+    let mut pipelines = JoinSet::new();
+    for pipeline in conf.pipelines {
+        info!("Starting pipeline {}", pipeline.name);
+        pipelines.spawn(PIPELINE.scope(pipeline.name.to_owned(), supervise(pipeline, shutdown.clone())));
+    }
+
+    // The deadline only starts once a shutdown has been requested.
+    let mut shutdown = shutdown;
+    let deadline = async {
+        if shutdown.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(Duration::from_millis(conf.shutdown.timeout)).await;
+    };
+    tokio::select! {
+        _ = async { while pipelines.join_next().await.is_some() {} } => {}
+        _ = deadline => warn!("{} pipeline(s) could not deliver their last batches within {} ms of the shutdown request, \
+            they will be received again", pipelines.len(), conf.shutdown.timeout),
+    }
+    info!("Log2Click stopped");
+    Ok(())
 }
 
-async fn read_config(path: &str) -> Result<HashMap<String, CObject>, SyncError> {
-    let settings = Config::builder()
-        .add_source(config::File::with_name(path))
-        .build()?;
-    Ok(settings.try_deserialize::<HashMap<String, CObject>>()?)
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("SIGTERM received, shutting down ..."),
+                    _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down ..."),
+                }
+            }
+            Err(e) => {
+                error!("SIGTERM cannot be handled: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Ctrl-C received, shutting down ...");
+    }
+}
+
+async fn replay(conf: &PipelineConfig, input: &str) -> Result<(), SyncError> {
+    let stages = Stages::create(conf)?;
+    stages.init().await?;
+    let source: Arc<dyn ReceiveTrait> = Arc::new(Replay::create(input, conf.receive.kafka.size)?);
+    PipBuilder::default()
+        .source(Some(source))
+        .filters(stages.filters)
+        .sink(Some(stages.sink))
+        .queue_size(conf.queue_size)
+        .build()?
+        .run().await?;
+    info!("{} has been replayed", input);
+    Ok(())
+}
+
+/// Refuses a configuration that `validate` reports problems in, for the `validate` subcommand
+/// and before a pipeline is run.
+fn validate(path: &str, conf: &Log2ClickConfig) -> Result<(), SyncError> {
+    let errors = conf.validate();
+    for error in &errors {
+        error!("{}", error);
+    }
+    if !errors.is_empty() {
+        return Err(SyncError::OptionParams(format!("{}: {} problem(s) found", path, errors.len())));
+    }
+    info!("{}: configuration is valid", path);
+    Ok(())
+}
+
+/// Table name => column name => type, of a `--schema` file.
+fn schema(path: &str) -> Result<HashMap<String, HashMap<String, String>>, SyncError> {
+    serde_yaml::from_reader(File::open(path)?)
+        .map_err(|e| SyncError::OptionParams(format!("{}: {}", path, e)))
+}
+
+async fn test_parse(conf: &PipelineConfig, input: &str, schema_file: Option<&str>) -> Result<(), SyncError> {
+    let reader: Box<dyn BufRead> = if input == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(input)?))
+    };
+    let json = Json::create(&conf.parser, OnError::fail(Stage::Json));
+    let regular = Regular::create(&conf.parser, OnError::fail(Stage::Regex))?;
+    let sink = Clickhouse::create(&conf.sender, OnError::fail(Stage::Sink))?;
+    if let Some(path) = schema_file {
+        sink.load_types(&schema(path)?)?;
+    }
+    let (mut total, mut failed) = (0, 0);
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        total += 1;
+        let number = index + 1;
+        // Plain log lines are wrapped into the envelope the Json filter expects.
+        let body = match serde_json::from_str::<Value>(&line) {
+            Ok(Value::Object(_)) => line,
+            _ => json!({ "log": line }).to_string(),
+        };
+        let message = LogMessage::new("test-parse", 0, index as i64, body);
+        let parsed = match json.process(vec![message]).await {
+            Ok(data) => regular.process(data).await,
+            Err(error) => Err(error),
+        };
+        let message = match parsed.map(|mut data| data.pop()) {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(error) => {
+                failed += 1;
+                println!("\x1b[31m[{}] UNMATCHED\x1b[0m {}", number, error);
+                continue;
+            }
+        };
+        let fields = message.map.clone().unwrap_or_default();
+        let missing: Vec<String> = sink.mapping(&message).unwrap_or(&conf.sender.mapping).iter()
+            .filter(|(_, field)| message.field(field).is_none())
+            .map(|(column, field)| format!("{} <- {}", column, field))
+            .collect();
+        let row = sink.row(&message);
+        match (&row, missing.is_empty()) {
+            (_, false) => println!("\x1b[33m[{}] MISSING\x1b[0m {}", number, missing.join(", ")),
+            (Err(error), true) => println!("\x1b[31m[{}] INVALID\x1b[0m {}", number, error),
+            (Ok(_), true) => println!("\x1b[32m[{}] OK\x1b[0m", number),
+        }
+        for (key, value) in &fields {
+            if key != "log" {
+                println!("    {} = {}", key, value);
+            }
+        }
+        match row {
+            Ok((table, row)) if missing.is_empty() => {
+                let row: Vec<String> = row.iter()
+                    .map(|(column, value)| match value {
+                        Cell::Null | Cell::Bool(_) | Cell::Int(_) | Cell::UInt(_) | Cell::Float(_)
+                        | Cell::Array(_) | Cell::Map(_) | Cell::Tuple(_) => format!("{}={}", column, value),
+                        _ => format!("{}={:?}", column, value.to_string()),
+                    })
+                    .collect();
+                println!("    => {} ({})", table, row.join(", "));
+            }
+            _ => failed += 1,
+        }
+    }
+    println!("{} line(s), {} ok, {} failed", total, total - failed, failed);
+    if failed > 0 {
+        return Err(SyncError::OptionParams(format!("{} of {} line(s) could not be parsed", failed, total)));
+    }
+    Ok(())
+}
+
+fn report(error: &SyncError) {
+    match error.source() {
+        None => {
+            error!("{}", error.to_string());
+        }
+        Some(error) => {
+            error!("{:?}", error);
+        }
+    }
+}
+
+async fn execute(args: Args) -> Result<(), SyncError> {
+    let mut conf = Log2ClickConfig::load(&args.config)?;
+    let pipeline = args.pipeline.as_deref();
+    if let Some(start_from) = &args.start_from {
+        for x in conf.pipelines.iter_mut().filter(|it| pipeline.is_none_or(|name| it.name == name)) {
+            x.receive.kafka.start_from = start_from.clone();
+        }
+    }
+    match args.command {
+        None => {
+            validate(&args.config, &conf)?;
+            try_main(conf).await
+        }
+        Some(Command::Validate) => validate(&args.config, &conf),
+        Some(Command::TestParse { input, schema }) => test_parse(conf.pipeline(pipeline)?, &input, schema.as_deref()).await,
+        Some(Command::Replay { input }) => {
+            validate(&args.config, &conf)?;
+            replay(conf.pipeline(pipeline)?, &input).await
+        }
+        Some(Command::GenerateDdl) => {
+            println!("{};", TableSchema::create(&conf.pipeline(pipeline)?.sender)?.create_table());
+            Ok(())
+        }
+    }
 }
 
 #[tokio::main]
@@ -61,7 +401,7 @@ async fn main() {
         .format(|buffer, record| {
             writeln!(
                 buffer,
-                "{} {} - {}",
+                "{} {} - {}{}",
                 Local::now().format("%Y-%m-%d %H:%M:%S"),
                 match record.level() {
                     Level::Trace => "\x1b[35mTRACE\x1b[0m",
@@ -70,37 +410,15 @@ async fn main() {
                     Level::Warn => "\x1b[33mWARN\x1b[0m",
                     Level::Error => "\x1b[31mERROR\x1b[0m",
                 },
+                PIPELINE.try_with(|name| format!("[{}] ", name)).unwrap_or_default(),
                 record.args()
             )
         })
         .init();
 
-    match read_config(&args.config).await {
-        Ok(conf) => {
-            match try_main(conf).await {
-                Ok(_) => {}
-                Err(error) => {
-                    match error.source() {
-                        None => {
-                            error!("{}", error.to_string());
-                        }
-                        Some(error) => {
-                            error!("{:?}", error);
-                        }
-                    }
-                }
-            };
-        }
-        Err(error) => {
-            match error.source() {
-                None => {
-                    error!("{}", error.to_string());
-                }
-                Some(error) => {
-                    error!("{:?}", error);
-                }
-            }
-        }
+    if let Err(error) = execute(args).await {
+        report(&error);
+        std::process::exit(1);
     }
 }
 
@@ -111,15 +429,29 @@ mod tests {
 
     #[test]
     fn test1() -> Result<(), SyncError> {
-        let settings = Config::builder()
-            .add_source(config::File::with_name("config.yaml"))
-            .build()?;
-
-        let conf = settings.try_deserialize::<HashMap<String, CObject>>()?;
+        let conf = Log2ClickConfig::from_yaml(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group_id: log2click
+    username: admin
+    password: admin
+parser:
+  regex: '(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d+) (\w+) \[([^]]+)\] \[([^]]*)\] ([^:]+) : ([\s\S]*)'
+  mapping: date, level, thread, trace_id, class, message
+sender:
+  mapping:
+    date: date
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+"#)?;
         let text = "2024-03-19 19:01:32.737 INFO [                main] [SS.:DD12] org.springframework.data.repository.config.RepositoryConfigurationDelegate : Multiple Spring Data modules found, entering strict repository configuration mode
     at 123441321
     at 12321312321321321";
-        let values = Regular::create(&conf)?.regex(text);
+        let values = Regular::create(&conf.pipelines[0].parser, OnError::fail(Stage::Regex))?.regex(text);
         for (index, x) in values.iter().enumerate() {
             println!("[{}] => {}", index, x);
         }
@@ -0,0 +1,94 @@
+use std::sync::LazyLock;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use prometheus::core::Collector;
+
+use crate::PIPELINE;
+use crate::error::SyncError;
+
+/// Metrics of every pipeline, each one labelled with the name of its pipeline.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Name of the pipeline of the current task, the `pipeline` label of the metrics.
+pub fn pipeline() -> String {
+    PIPELINE.try_with(|name| name.to_owned()).unwrap_or_default()
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// Messages received from Kafka, by topic and partition.
+    pub consumed: IntCounterVec,
+    /// Messages per batch pulled from the source.
+    pub batch_size: HistogramVec,
+    /// Records rejected, by stage.
+    pub rejected: IntCounterVec,
+    /// Rejected records sent to the dead-letter target, by stage.
+    pub dead_letters: IntCounterVec,
+    /// Rows written, by table.
+    pub inserted: IntCounterVec,
+    /// Duration of every insert attempt, by table.
+    pub insert_duration: HistogramVec,
+    /// Failed attempts that are retried, by operation.
+    pub retries: IntCounterVec,
+    /// Messages between the consumer position and the end of the partition, from the
+    /// statistics of librdkafka.
+    pub consumer_lag: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some(String::from("log2click")), None)
+            .expect("the prefix is a valid metric name");
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            register(&registry, IntCounterVec::new(Opts::new(name, help), labels))
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: Vec<f64>| {
+            register(&registry, HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels))
+        };
+        Metrics {
+            consumed: counter("messages_consumed_total", "Messages received from Kafka.", &["pipeline", "topic", "partition"]),
+            batch_size: histogram("batch_size", "Messages per batch pulled from the source.", &["pipeline"],
+                vec![1.0, 10.0, 100.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 50000.0]),
+            rejected: counter("records_rejected_total", "Records rejected by a stage.", &["pipeline", "stage"]),
+            dead_letters: counter("dead_letters_total", "Rejected records sent to the dead-letter target.", &["pipeline", "stage"]),
+            inserted: counter("rows_inserted_total", "Rows written to Clickhouse.", &["pipeline", "table"]),
+            insert_duration: histogram("insert_duration_seconds", "Duration of the insert attempts.", &["pipeline", "table"],
+                vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            retries: counter("retries_total", "Failed attempts that are retried.", &["pipeline", "operation"]),
+            consumer_lag: register(&registry, IntGaugeVec::new(
+                Opts::new("consumer_lag", "Messages not consumed yet, from the librdkafka statistics."),
+                &["pipeline", "topic", "partition"])),
+            registry,
+        }
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> Result<String, SyncError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| SyncError::OptionParams(format!("Metrics could not be encoded: {}", e)))?;
+        String::from_utf8(buffer).map_err(|e| SyncError::OptionParams(e.to_string()))
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: prometheus::Result<T>) -> T {
+    // The names and labels are constants, neither can be invalid nor registered twice.
+    let metric = metric.expect("valid metric");
+    registry.register(Box::new(metric.clone())).expect("metric registered once");
+    metric
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() -> Result<(), SyncError> {
+        METRICS.consumed.with_label_values(&["test", "logs", "3"]).inc_by(2);
+        METRICS.insert_duration.with_label_values(&["test", "app"]).observe(0.02);
+        let text = METRICS.render()?;
+        assert!(text.contains(r#"log2click_messages_consumed_total{partition="3",pipeline="test",topic="logs"} 2"#), "{}", text);
+        assert!(text.contains(r#"log2click_insert_duration_seconds_bucket{pipeline="test",table="app",le="0.025"} 1"#), "{}", text);
+        Ok(())
+    }
+}
@@ -1,94 +1,261 @@
-use std::collections::HashMap;
-
 use async_trait::async_trait;
-use log::{debug, warn};
+use log::debug;
 use regex::Regex;
-use serde_json::Value;
+use serde_json::{Map, Value};
 
-use crate::{CObject, Filter, LogMessage};
+use crate::{Filter, LogMessage, PIPELINE};
+use crate::config::ParserConfig;
 use crate::error::SyncError;
+use crate::policy::{OnError, Outcome};
+
+/// Smallest share of a batch handed to a worker, below it the thread hop costs more than it saves.
+const MIN_CHUNK: usize = 256;
+
+/// Applies `parse` to every message of a batch, keeping their order. Large batches are split
+/// across up to `workers` blocking threads.
+async fn parse_batch<T, F>(data: Vec<LogMessage>, workers: usize, parse: F) -> Result<Vec<(LogMessage, T)>, SyncError>
+where
+    T: Send + 'static,
+    F: Fn(&LogMessage) -> T + Clone + Send + 'static,
+{
+    let chunks = workers.min(data.len() / MIN_CHUNK);
+    if chunks <= 1 {
+        return Ok(parse_all(&parse, data));
+    }
+    let length = data.len();
+    let size = length.div_ceil(chunks);
+    let pipeline = PIPELINE.try_with(|name| name.to_owned()).ok();
+    let mut data = data.into_iter();
+    let mut tasks = Vec::with_capacity(chunks);
+    loop {
+        let chunk: Vec<LogMessage> = data.by_ref().take(size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let parse = parse.clone();
+        let pipeline = pipeline.clone();
+        tasks.push(tokio::task::spawn_blocking(move || {
+            match pipeline {
+                Some(name) => PIPELINE.sync_scope(name, || parse_all(&parse, chunk)),
+                None => parse_all(&parse, chunk),
+            }
+        }));
+    }
+    let mut result = Vec::with_capacity(length);
+    for task in tasks {
+        let parsed = task.await.map_err(|e| SyncError::OptionParams(format!("Parser worker ended abnormally: {}", e)))?;
+        result.extend(parsed);
+    }
+    Ok(result)
+}
+
+fn parse_all<T>(parse: &impl Fn(&LogMessage) -> T, data: Vec<LogMessage>) -> Vec<(LogMessage, T)> {
+    data.into_iter()
+        .map(|x| {
+            let value = parse(&x);
+            (x, value)
+        })
+        .collect()
+}
+
+pub struct Json {
+    workers: usize,
+    on_error: OnError,
+}
+
+impl Json {
+    pub fn create(conf: &ParserConfig, on_error: OnError) -> Json {
+        Json { workers: conf.workers, on_error }
+    }
 
-pub struct Json;
+    fn parse(body: &str) -> Result<(String, Map<String, Value>), SyncError> {
+        let json_value: Value = serde_json::from_str(body)?;
+        let map = json_value.as_object().ok_or(SyncError::MissingParams("Message is not a JSON object."))?;
+        let log = map.get("log").and_then(|it| it.as_str())
+            .ok_or(SyncError::MissingParams("Message has no 'log' string field."))?.trim().to_string();
+        Ok((log, map.to_owned()))
+    }
+}
 
-#[async_trait(? Send)]
+#[async_trait]
 impl Filter for Json {
-    async fn process(&self, mut data: Vec<LogMessage>) -> Result<Vec<LogMessage>, SyncError> {
-        for x in &mut data {
-            let json_value: Value = serde_json::from_str(&x.body)?;
-            let map = json_value.as_object().ok_or(SyncError::Option)?;
-            let log = map.get("log").ok_or(SyncError::Option)?.as_str().ok_or(SyncError::Option)?.trim().to_string();
-            debug!("[Log] {}", &log);
-            x.log = Some(log);
-            x.map = Some(map.to_owned());
+    async fn process(&self, data: Vec<LogMessage>) -> Result<Vec<LogMessage>, SyncError> {
+        let mut result = Vec::with_capacity(data.len());
+        for (mut x, parsed) in parse_batch(data, self.workers, |x| Json::parse(&x.body)).await? {
+            match parsed {
+                Ok((log, map)) => {
+                    debug!("[Log] {}", &log);
+                    x.log = Some(log);
+                    x.map = Some(map);
+                }
+                Err(e) => match self.on_error.reject(&x, e).await? {
+                    Outcome::Drop => continue,
+                    // The whole payload is taken as the log line.
+                    Outcome::Default => {
+                        let log = x.body.trim().to_string();
+                        let mut map = Map::new();
+                        map.insert(String::from("log"), Value::String(log.to_owned()));
+                        x.log = Some(log);
+                        x.map = Some(map);
+                    }
+                },
+            }
+            result.push(x);
         }
-        Ok(data)
+        Ok(result)
     }
 }
 
 pub struct Regular {
     regex: Regex,
     mapping: Vec<String>,
+    workers: usize,
+    on_error: OnError,
 }
 
 impl Regular {
-    pub fn create(conf: &HashMap<String, CObject>) -> Result<Regular, SyncError> {
-        let conf: Option<HashMap<String, CObject>> = conf.get("parser")
-            .ok_or(SyncError::MissingParams("Environment variable 'parser' could not be found."))?.into();
-        let conf = conf
-            .ok_or(SyncError::MissingParams("Environment variable 'parser' could not be found."))?;
-        let regex: String = conf.get("regex")
-            .ok_or(SyncError::MissingParams("Environment variable 'parser.regex' could not be found."))?.into();
-        let mapping: String = conf.get("mapping")
-            .ok_or(SyncError::MissingParams("Environment variable 'parser.mapping' could not be found."))?.into();
-        debug!("[Regex] Expression : {}", regex);
-        Ok(Regular { regex: Regex::new(&regex)?, mapping: mapping.split(",").map(|it| it.trim().to_owned()).collect() })
+    pub fn create(conf: &ParserConfig, on_error: OnError) -> Result<Regular, SyncError> {
+        debug!("[Regex] Expression : {}", conf.regex);
+        Ok(Regular { regex: Regex::new(&conf.regex)?, mapping: conf.mapping.clone(), workers: conf.workers, on_error })
     }
     pub fn regex(&self, text: &str) -> Vec<String> {
-        return match self.regex.captures(text) {
-            None => {
-                warn!("{}", text);
-                Vec::default()
-            }
-            Some(cap) => {
-                let mut values: Vec<String> = cap.iter().map(|c| {
-                    match c {
-                        None => String::default(),
-                        Some(val) => val.as_str().to_owned()
-                    }
-                }).collect();
-                if values.len() > 0 {
-                    values.remove(0);
-                }
-                debug!("[Regex] Analysis Results : {:?}", values);
-                values
+        Regular::captures(&self.regex, text).unwrap_or_default()
+    }
+
+    /// The captured groups, `None` when `text` does not match; a regex without groups matches
+    /// with no values.
+    fn captures(regex: &Regex, text: &str) -> Option<Vec<String>> {
+        // Rejected, and logged, by the error policy when there is no match.
+        let cap = regex.captures(text)?;
+        let values: Vec<String> = cap.iter().skip(1).map(|c| {
+            match c {
+                None => String::default(),
+                Some(val) => val.as_str().to_owned()
             }
-        };
+        }).collect();
+        debug!("[Regex] Analysis Results : {:?}", values);
+        Some(values)
     }
 }
 
-#[async_trait(? Send)]
+#[async_trait]
 impl Filter for Regular {
-    async fn process(&self, mut data: Vec<LogMessage>) -> Result<Vec<LogMessage>, SyncError> {
-        for x in &mut data {
-            match &mut x.map {
-                None => continue,
-                Some(attr) => {
-                    let text = &x.log.to_owned().ok_or(SyncError::Option)?;
-                    let values = self.regex(text);
-                    if self.mapping.len() >= values.len() {
-                        for (index, key) in self.mapping.iter().enumerate() {
-                            let value = values.get(index).ok_or(SyncError::Option)?;
-                            attr.insert(key.to_owned(), Value::String(value.to_owned()));
-                        }
+    async fn process(&self, data: Vec<LogMessage>) -> Result<Vec<LogMessage>, SyncError> {
+        let regex = self.regex.clone();
+        let parsed = parse_batch(data, self.workers, move |x| match (&x.map, &x.log) {
+            (Some(_), Some(text)) => Regular::captures(&regex, text),
+            _ => None,
+        }).await?;
+        let mut result = Vec::with_capacity(parsed.len());
+        for (mut x, values) in parsed {
+            let text = match (&x.map, &x.log) {
+                (None, _) => {
+                    result.push(x);
+                    continue;
+                }
+                (Some(_), None) => None,
+                (Some(_), Some(text)) => Some(text.to_owned()),
+            };
+            let values = match values {
+                Some(values) if self.mapping.len() <= values.len() => values,
+                values => {
+                    let error = match text {
+                        None => SyncError::MissingParams("Message has no log to parse."),
+                        Some(text) if values.is_none() => SyncError::OptionParams(format!("Log does not match 'parser.regex': {}", text)),
+                        Some(_) => SyncError::MissingParams("'parser.mapping' names more fields than 'parser.regex' captures."),
+                    };
+                    match self.on_error.reject(&x, error).await? {
+                        Outcome::Drop => continue,
+                        Outcome::Default => Vec::default(),
                     }
                 }
+            };
+            if let Some(attr) = &mut x.map {
+                for (index, key) in self.mapping.iter().enumerate() {
+                    let value = values.get(index).cloned().unwrap_or_default();
+                    attr.insert(key.to_owned(), Value::String(value));
+                }
             }
+            result.push(x);
         }
-        Ok(data)
+        Ok(result)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
+    use crate::config::ErrorPolicy;
+    use crate::policy::{LogDeadLetter, Stage};
 
+    use super::*;
 
+    fn message(offset: i64, body: &str) -> LogMessage {
+        LogMessage::new("logs", 0, offset, body)
+    }
+
+    fn on_error(stage: Stage, policy: ErrorPolicy) -> OnError {
+        OnError::new(stage, policy, Arc::new(LogDeadLetter))
+    }
 
+    fn parser(workers: usize) -> ParserConfig {
+        ParserConfig {
+            regex: String::from(r"(\w+) : (.+)"),
+            mapping: vec![String::from("level"), String::from("message")],
+            envelope: None,
+            workers,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_records_follow_the_policy() -> Result<(), SyncError> {
+        let json = Json::create(&parser(1), on_error(Stage::Json, ErrorPolicy::Skip));
+        let regular = Regular::create(&parser(1), on_error(Stage::Regex, ErrorPolicy::DefaultValue))?;
+        let data = vec![
+            message(0, r#"{"log": "INFO : started"}"#),
+            message(1, "not json"),
+            message(2, r#"{"log": "unstructured"}"#),
+        ];
+        let data = regular.process(json.process(data).await?).await?;
+        assert_eq!(data.iter().map(|it| it.offset).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(data[0].map.as_ref().unwrap()["message"], "started");
+        assert_eq!(data[1].map.as_ref().unwrap()["level"], "");
+        assert_eq!(json.on_error.rejected(), 1);
+        assert!(Json::create(&parser(1), OnError::fail(Stage::Json)).process(vec![message(1, "not json")]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn regex_without_groups_filters_lines() -> Result<(), SyncError> {
+        let conf = ParserConfig { regex: String::from("^ERROR"), mapping: Vec::new(), ..parser(1) };
+        let json = Json::create(&conf, OnError::fail(Stage::Json));
+        let regular = Regular::create(&conf, on_error(Stage::Regex, ErrorPolicy::Skip))?;
+        let data = vec![
+            message(0, r#"{"log": "ERROR disk full"}"#),
+            message(1, r#"{"log": "INFO started"}"#),
+        ];
+        let data = regular.process(json.process(data).await?).await?;
+        assert_eq!(data.iter().map(|it| it.offset).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(regular.on_error.rejected(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parallel_parsing_keeps_the_order() -> Result<(), SyncError> {
+        let json = Json::create(&parser(4), on_error(Stage::Json, ErrorPolicy::Skip));
+        let regular = Regular::create(&parser(4), on_error(Stage::Regex, ErrorPolicy::Skip))?;
+        let data = (0..1000)
+            .map(|offset| match offset % 10 {
+                0 => message(offset, "not json"),
+                _ => message(offset, &format!(r#"{{"log": "INFO : message {}"}}"#, offset)),
+            })
+            .collect();
+        let data = regular.process(json.process(data).await?).await?;
+        assert_eq!(data.len(), 900);
+        assert!(data.iter().all(|it| it.map.as_ref().unwrap()["message"] == format!("message {}", it.offset)));
+        assert!(data.windows(2).all(|it| it[0].offset < it[1].offset));
+        assert_eq!(json.on_error.rejected(), 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use chrono::Local;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{DeadLetterTrait, LogMessage};
+use crate::config::ErrorPolicy;
+use crate::error::SyncError;
+use crate::metrics::{METRICS, pipeline};
+
+/// Pipeline stage that can reject a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Json,
+    Regex,
+    Sink,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Json => write!(f, "json"),
+            Stage::Regex => write!(f, "regex"),
+            Stage::Sink => write!(f, "sink"),
+        }
+    }
+}
+
+/// A rejected record together with where and why it was rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Timestamp of the Kafka message, `timestamp` being the time of the rejection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub stage: Stage,
+    pub error: String,
+    pub timestamp: String,
+}
+
+impl DeadLetter {
+    pub fn create(message: &LogMessage, stage: Stage, error: &SyncError) -> DeadLetter {
+        DeadLetter {
+            topic: message.topic.to_owned(),
+            partition: message.partition,
+            offset: message.offset,
+            key: message.key.clone(),
+            message_timestamp: message.timestamp,
+            headers: message.headers.clone(),
+            body: message.body.to_owned(),
+            stage,
+            error: error.to_string(),
+            timestamp: Local::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Dead-letter target used when none is configured: the record is only logged.
+pub struct LogDeadLetter;
+
+#[async_trait]
+impl DeadLetterTrait for LogDeadLetter {
+    async fn send(&self, letter: DeadLetter) -> Result<(), SyncError> {
+        error!("[DeadLetter] {}", serde_json::to_string(&letter)?);
+        Ok(())
+    }
+}
+
+/// What happens to a rejected record that the policy lets through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Drop the record from the batch.
+    Drop,
+    /// Keep the record, filling what could not be produced with default values.
+    Default,
+}
+
+/// Applies the error policy of one stage and counts the records it rejected.
+pub struct OnError {
+    stage: Stage,
+    policy: ErrorPolicy,
+    dead_letter: Arc<dyn DeadLetterTrait>,
+    rejected: AtomicU64,
+}
+
+impl OnError {
+    pub fn new(stage: Stage, policy: ErrorPolicy, dead_letter: Arc<dyn DeadLetterTrait>) -> OnError {
+        OnError { stage, policy, dead_letter, rejected: AtomicU64::new(0) }
+    }
+
+    /// Policy that stops the pipeline on the first error.
+    pub fn fail(stage: Stage) -> OnError {
+        OnError::new(stage, ErrorPolicy::Fail, Arc::new(LogDeadLetter))
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Number of records rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub async fn reject(&self, message: &LogMessage, error: SyncError) -> Result<Outcome, SyncError> {
+        // Counted under every policy, failing ones included.
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        let stage = self.stage.to_string();
+        METRICS.rejected.with_label_values(&[&pipeline(), &stage]).inc();
+        if self.policy == ErrorPolicy::Fail {
+            return Err(error);
+        }
+        warn!("[{}] {:?} {}-{}@{} ({} so far): {}", self.stage, self.policy,
+            message.topic, message.partition, message.offset, rejected, error);
+        match self.policy {
+            ErrorPolicy::DefaultValue => Ok(Outcome::Default),
+            ErrorPolicy::DeadLetter => {
+                self.dead_letter.send(DeadLetter::create(message, self.stage, &error)).await?;
+                METRICS.dead_letters.with_label_values(&[&pipeline(), &stage]).inc();
+                Ok(Outcome::Drop)
+            }
+            ErrorPolicy::Skip | ErrorPolicy::Fail => Ok(Outcome::Drop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Keeps the letters it is sent.
+    #[derive(Default)]
+    struct Letters(Mutex<Vec<DeadLetter>>);
+
+    #[async_trait]
+    impl DeadLetterTrait for Letters {
+        async fn send(&self, letter: DeadLetter) -> Result<(), SyncError> {
+            self.0.lock().unwrap().push(letter);
+            Ok(())
+        }
+    }
+
+    fn on_error(policy: ErrorPolicy) -> (OnError, Arc<Letters>) {
+        let letters = Arc::new(Letters::default());
+        (OnError::new(Stage::Regex, policy, letters.clone()), letters)
+    }
+
+    fn message() -> LogMessage {
+        LogMessage { key: Some(String::from("order-42")), ..LogMessage::new("logs", 1, 7, "not a log line") }
+    }
+
+    #[tokio::test]
+    async fn fail_returns_the_error() {
+        let (on_error, letters) = on_error(ErrorPolicy::Fail);
+        let counted = || METRICS.rejected.with_label_values(&[&pipeline(), &on_error.stage().to_string()]).get();
+        let before = counted();
+        let result = on_error.reject(&message(), SyncError::OptionParams(String::from("no match"))).await;
+        assert_eq!(result.map_err(|e| e.to_string()), Err(String::from("no match")));
+        assert_eq!(on_error.rejected(), 1);
+        assert!(counted() > before);
+        assert!(letters.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_drops_the_record() -> Result<(), SyncError> {
+        let (on_error, letters) = on_error(ErrorPolicy::Skip);
+        assert_eq!(on_error.reject(&message(), SyncError::Option).await?, Outcome::Drop);
+        assert_eq!(on_error.reject(&message(), SyncError::Option).await?, Outcome::Drop);
+        assert_eq!(on_error.rejected(), 2);
+        assert!(letters.0.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dead_letter_hands_the_record_over() -> Result<(), SyncError> {
+        let (on_error, letters) = on_error(ErrorPolicy::DeadLetter);
+        assert_eq!(on_error.reject(&message(), SyncError::OptionParams(String::from("no match"))).await?, Outcome::Drop);
+        assert_eq!(on_error.rejected(), 1);
+        let letters = letters.0.lock().unwrap();
+        let [letter] = letters.as_slice() else {
+            panic!("one letter expected, got {:?}", letters);
+        };
+        assert_eq!((letter.topic.as_str(), letter.partition, letter.offset), ("logs", 1, 7));
+        assert_eq!((letter.body.as_str(), letter.key.as_deref()), ("not a log line", Some("order-42")));
+        assert_eq!((letter.stage, letter.error.as_str()), (Stage::Regex, "no match"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn default_value_keeps_the_record() -> Result<(), SyncError> {
+        let (on_error, letters) = on_error(ErrorPolicy::DefaultValue);
+        assert_eq!(on_error.reject(&message(), SyncError::Option).await?, Outcome::Default);
+        assert_eq!(on_error.rejected(), 1);
+        assert!(letters.0.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
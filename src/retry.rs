@@ -0,0 +1,128 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use rand::Rng;
+use tokio::sync::watch;
+
+use crate::config::RetryConfig;
+use crate::error::SyncError;
+use crate::metrics::{METRICS, pipeline};
+
+/// Retries an operation on retryable errors with jittered exponential backoff.
+///
+/// A retryable failure is given up on after `max-attempts` attempts only, by default never, so
+/// the batch in flight is neither lost nor skipped. After `threshold` consecutive failures the
+/// circuit opens and stays open until an attempt succeeds, which lets the pipeline pause its
+/// source in the meantime.
+pub struct Retry {
+    conf: RetryConfig,
+    open: watch::Sender<bool>,
+}
+
+impl Retry {
+    pub fn new(conf: &RetryConfig) -> Retry {
+        Retry { conf: conf.clone(), open: watch::Sender::new(false) }
+    }
+
+    /// `true` while the circuit is open.
+    pub fn circuit(&self) -> watch::Receiver<bool> {
+        self.open.subscribe()
+    }
+
+    /// Delay after the `failures`-th consecutive failure: at least half of the exponential
+    /// backoff, the other half is random so that restarted consumers do not retry in lockstep.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let backoff = self.conf.backoff
+            .saturating_mul(1u64 << (failures - 1).min(32))
+            .min(self.conf.max_backoff);
+        let half = backoff / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=backoff - half))
+    }
+
+    pub async fn run<T, F, Fut>(&self, name: &str, mut attempt: F) -> Result<T, SyncError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=Result<T, SyncError>>,
+    {
+        let mut failures = 0;
+        loop {
+            let error = match attempt().await {
+                Err(e) if e.is_retryable() => e,
+                result => {
+                    if self.open.send_replace(false) && result.is_ok() {
+                        info!("[{}] Available again, circuit closed", name);
+                    }
+                    return result;
+                }
+            };
+            failures += 1;
+            if self.conf.max_attempts.is_some_and(|max| failures >= max) {
+                error!("[{}] Giving up after {} attempts: {}", name, failures, error);
+                return Err(error);
+            }
+            METRICS.retries.with_label_values(&[&pipeline(), name]).inc();
+            let delay = self.delay(failures);
+            warn!("[{}] Attempt {} failed, retrying in {:?}: {}", name, failures, delay, error);
+            if failures >= self.conf.threshold && !self.open.send_replace(true) {
+                warn!("[{}] Unavailable, circuit open after {} consecutive failures", name, failures);
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use clickhouse::error::Error;
+
+    use super::*;
+
+    fn retry() -> Retry {
+        Retry::new(&RetryConfig { backoff: 1, max_backoff: 4, threshold: 2, max_attempts: None })
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() -> Result<(), SyncError> {
+        let retry = retry();
+        let mut circuit = retry.circuit();
+        let attempts = Cell::new(0);
+        let value = retry.run("Test", || async {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1..=2 => Err(SyncError::ClickhouseError(Error::TimedOut)),
+                _ => Ok(attempts.get()),
+            }
+        }).await?;
+        assert_eq!(value, 3);
+        // Opened after the second failure, closed by the success.
+        assert!(circuit.has_changed().unwrap());
+        assert!(!*circuit.borrow_and_update());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_attempts() {
+        let retry = Retry::new(&RetryConfig { max_attempts: Some(3), ..retry().conf });
+        let attempts = Cell::new(0);
+        let result: Result<(), SyncError> = retry.run("Test", || async {
+            attempts.set(attempts.get() + 1);
+            Err(SyncError::ClickhouseError(Error::TimedOut))
+        }).await;
+        assert!(result.is_err_and(|e| e.is_retryable()));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn schema_errors_are_not_retried() {
+        let attempts = Cell::new(0);
+        let result: Result<(), SyncError> = retry().run("Test", || async {
+            attempts.set(attempts.get() + 1);
+            Err(SyncError::ClickhouseError(Error::BadResponse(String::from("Code: 60. DB::Exception: Unknown table."))))
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,554 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use chrono_tz::Tz;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeTuple;
+use serde_json::Value;
+
+/// Type of a Clickhouse column, as reported by `system.columns`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnType {
+    String,
+    FixedString(usize),
+    /// Signed integer of the given number of bits.
+    Int(u8),
+    UInt(u8),
+    Float(u8),
+    Bool,
+    Date,
+    Date32,
+    /// Without a timezone, the one of the server applies.
+    DateTime(Option<Tz>),
+    DateTime64(u8, Option<Tz>),
+    Uuid,
+    IPv4,
+    IPv6,
+    Enum8(Vec<(String, i16)>),
+    Enum16(Vec<(String, i16)>),
+    Nullable(Box<ColumnType>),
+    LowCardinality(Box<ColumnType>),
+    Array(Box<ColumnType>),
+    Map(Box<ColumnType>, Box<ColumnType>),
+    /// Elements with their name, when the tuple is named.
+    Tuple(Vec<(Option<String>, ColumnType)>),
+    /// The `JSON` type, written as text.
+    Json,
+    /// Any other type, bound as text for Clickhouse to parse.
+    Other(String),
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (name, args) = match text.find('(') {
+            Some(index) if text.ends_with(')') => (&text[..index], split_args(&text[index + 1..text.len() - 1])),
+            _ => (text, Vec::new()),
+        };
+        let invalid = || format!("Unsupported column type '{}'.", text);
+        // Clickhouse allows 9 digits at most, the nanoseconds.
+        let precision = |arg: &str| arg.parse().ok().filter(|it| *it <= 9).ok_or_else(invalid);
+        Ok(match (name, args.as_slice()) {
+            ("String", []) => ColumnType::String,
+            ("FixedString", [size]) => ColumnType::FixedString(size.parse().map_err(|_| invalid())?),
+            ("Int8" | "Int16" | "Int32" | "Int64", []) => ColumnType::Int(name[3..].parse().map_err(|_| invalid())?),
+            ("UInt8" | "UInt16" | "UInt32" | "UInt64", []) => ColumnType::UInt(name[4..].parse().map_err(|_| invalid())?),
+            ("Float32" | "Float64", []) => ColumnType::Float(name[5..].parse().map_err(|_| invalid())?),
+            ("Bool" | "Boolean", []) => ColumnType::Bool,
+            ("Date", []) => ColumnType::Date,
+            ("Date32", []) => ColumnType::Date32,
+            ("DateTime", []) => ColumnType::DateTime(None),
+            ("DateTime", [tz]) => ColumnType::DateTime(Some(timezone(tz)?)),
+            ("DateTime64", [digits]) => ColumnType::DateTime64(precision(digits)?, None),
+            ("DateTime64", [digits, tz]) => ColumnType::DateTime64(precision(digits)?, Some(timezone(tz)?)),
+            ("UUID", []) => ColumnType::Uuid,
+            ("IPv4", []) => ColumnType::IPv4,
+            ("IPv6", []) => ColumnType::IPv6,
+            ("Enum8", values) => ColumnType::Enum8(enum_values(values).ok_or_else(invalid)?),
+            ("Enum16", values) => ColumnType::Enum16(enum_values(values).ok_or_else(invalid)?),
+            ("Nullable", [inner]) => ColumnType::Nullable(Box::new(inner.parse()?)),
+            ("LowCardinality", [inner]) => ColumnType::LowCardinality(Box::new(inner.parse()?)),
+            ("Array", [inner]) => ColumnType::Array(Box::new(inner.parse()?)),
+            ("Map", [key, value]) => ColumnType::Map(Box::new(key.parse()?), Box::new(value.parse()?)),
+            ("Tuple", elements) => ColumnType::Tuple(elements.iter().map(|it| tuple_element(it)).collect::<Result<_, _>>()?),
+            ("JSON", _) => ColumnType::Json,
+            _ => ColumnType::Other(text.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values = |values: &[(String, i16)]| values.iter()
+            .map(|(name, value)| format!("'{}' = {}", name.replace('\'', "\\'"), value))
+            .collect::<Vec<String>>()
+            .join(", ");
+        match self {
+            ColumnType::String => write!(f, "String"),
+            ColumnType::FixedString(size) => write!(f, "FixedString({})", size),
+            ColumnType::Int(bits) => write!(f, "Int{}", bits),
+            ColumnType::UInt(bits) => write!(f, "UInt{}", bits),
+            ColumnType::Float(bits) => write!(f, "Float{}", bits),
+            ColumnType::Bool => write!(f, "Bool"),
+            ColumnType::Date => write!(f, "Date"),
+            ColumnType::Date32 => write!(f, "Date32"),
+            ColumnType::DateTime(None) => write!(f, "DateTime"),
+            ColumnType::DateTime(Some(tz)) => write!(f, "DateTime('{}')", tz),
+            ColumnType::DateTime64(precision, None) => write!(f, "DateTime64({})", precision),
+            ColumnType::DateTime64(precision, Some(tz)) => write!(f, "DateTime64({}, '{}')", precision, tz),
+            ColumnType::Uuid => write!(f, "UUID"),
+            ColumnType::IPv4 => write!(f, "IPv4"),
+            ColumnType::IPv6 => write!(f, "IPv6"),
+            ColumnType::Enum8(list) => write!(f, "Enum8({})", values(list)),
+            ColumnType::Enum16(list) => write!(f, "Enum16({})", values(list)),
+            ColumnType::Nullable(inner) => write!(f, "Nullable({})", inner),
+            ColumnType::LowCardinality(inner) => write!(f, "LowCardinality({})", inner),
+            ColumnType::Array(inner) => write!(f, "Array({})", inner),
+            ColumnType::Map(key, value) => write!(f, "Map({}, {})", key, value),
+            ColumnType::Tuple(elements) => {
+                let elements: Vec<String> = elements.iter()
+                    .map(|(name, kind)| match name {
+                        Some(name) => format!("{} {}", name, kind),
+                        None => kind.to_string(),
+                    })
+                    .collect();
+                write!(f, "Tuple({})", elements.join(", "))
+            }
+            ColumnType::Json => write!(f, "JSON"),
+            ColumnType::Other(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// Arguments of a parametric type, split on the commas outside of nested types and quotes.
+fn split_args(text: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let (mut depth, mut quoted, mut escaped, mut start) = (0, false, false, 0);
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                args.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        args.push(text[start..].trim());
+    }
+    args
+}
+
+/// `name Type` or `Type`, a name is followed by a space before any parenthesis.
+fn tuple_element(text: &str) -> Result<(Option<String>, ColumnType), String> {
+    match text.split_once(char::is_whitespace) {
+        Some((name, kind)) if !name.contains('(') => Ok((Some(name.trim_matches('`').to_owned()), kind.parse()?)),
+        _ => Ok((None, text.parse()?)),
+    }
+}
+
+fn unquote(text: &str) -> Option<String> {
+    let text = text.trim().strip_prefix('\'')?.strip_suffix('\'')?;
+    Some(text.replace("\\'", "'").replace("\\\\", "\\"))
+}
+
+fn timezone(text: &str) -> Result<Tz, String> {
+    let name = unquote(text).ok_or_else(|| format!("Invalid timezone {}.", text))?;
+    name.parse().map_err(|_| format!("Unknown timezone '{}'.", name))
+}
+
+/// `'name' = value` pairs of an enum.
+fn enum_values(args: &[&str]) -> Option<Vec<(String, i16)>> {
+    args.iter()
+        .map(|arg| {
+            let (name, value) = arg.rsplit_once('=')?;
+            Some((unquote(name)?, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Value converted to the type of its column.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Date(NaiveDate),
+    /// Wall-clock time in the timezone of the column, truncated to the given precision.
+    DateTime(NaiveDateTime, u8),
+    Uuid(u128),
+    IPv4(Ipv4Addr),
+    IPv6(Ipv6Addr),
+    /// Name and value of an enum element.
+    Enum(String, i16),
+    Array(Vec<Cell>),
+    Map(Vec<(Cell, Cell)>),
+    Tuple(Vec<Cell>),
+}
+
+impl Cell {
+    /// Whether the value is or holds a NULL, which the clickhouse crate cannot bind.
+    pub fn has_null(&self) -> bool {
+        match self {
+            Cell::Null => true,
+            Cell::Array(values) | Cell::Tuple(values) => values.iter().any(Cell::has_null),
+            Cell::Map(entries) => entries.iter().any(|(key, value)| key.has_null() || value.has_null()),
+            _ => false,
+        }
+    }
+
+    /// SQL literal of the value, written out in the query in place of a bound parameter, with
+    /// maps as arrays of key-value tuples. `?` is escaped so as not to be taken for a parameter.
+    pub fn literal(&self) -> String {
+        let join = |values: &[Cell]| values.iter().map(Cell::literal).collect::<Vec<String>>().join(", ");
+        match self {
+            Cell::Null | Cell::Bool(_) | Cell::Int(_) | Cell::UInt(_) => self.to_string(),
+            // nan and inf.
+            Cell::Float(value) => value.to_string().to_lowercase(),
+            Cell::Array(values) => format!("[{}]", join(values)),
+            Cell::Map(entries) => {
+                let entries: Vec<String> = entries.iter()
+                    .map(|(key, value)| format!("({}, {})", key.literal(), value.literal()))
+                    .collect();
+                format!("[{}]", entries.join(", "))
+            }
+            Cell::Tuple(values) => format!("({})", join(values)),
+            _ => format!("'{}'", self.to_string().replace('\\', "\\\\").replace('\'', "\\'").replace('?', "\\x3F")),
+        }
+    }
+
+    /// Value as an element of an array, a map or a tuple, where text is quoted.
+    fn nested(&self) -> String {
+        match self {
+            Cell::Null | Cell::Bool(_) | Cell::Int(_) | Cell::UInt(_) | Cell::Float(_)
+            | Cell::Array(_) | Cell::Map(_) | Cell::Tuple(_) => self.to_string(),
+            _ => format!("'{}'", self.to_string().replace('\\', "\\\\").replace('\'', "\\'")),
+        }
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cell::Null => write!(f, "NULL"),
+            Cell::Bool(value) => write!(f, "{}", value),
+            Cell::Int(value) => write!(f, "{}", value),
+            Cell::UInt(value) => write!(f, "{}", value),
+            Cell::Float(value) => write!(f, "{}", value),
+            Cell::String(value) => write!(f, "{}", value),
+            Cell::Date(value) => write!(f, "{}", value.format("%Y-%m-%d")),
+            Cell::DateTime(value, 0) => write!(f, "{}", value.format("%Y-%m-%d %H:%M:%S")),
+            Cell::DateTime(value, precision) => {
+                let precision = (*precision).min(9);
+                let fraction = value.nanosecond() / 10u32.pow(9 - precision as u32);
+                write!(f, "{}.{:0width$}", value.format("%Y-%m-%d %H:%M:%S"), fraction, width = precision as usize)
+            }
+            Cell::Uuid(value) => {
+                let hex = format!("{:032x}", value);
+                write!(f, "{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+            }
+            Cell::IPv4(value) => write!(f, "{}", value),
+            Cell::IPv6(value) => write!(f, "{}", value),
+            Cell::Enum(name, _) => write!(f, "{}", name),
+            Cell::Array(values) => {
+                let values: Vec<String> = values.iter().map(Cell::nested).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Cell::Map(entries) => {
+                let entries: Vec<String> = entries.iter()
+                    .map(|(key, value)| format!("{}: {}", key.nested(), value.nested()))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Cell::Tuple(values) => {
+                let values: Vec<String> = values.iter().map(Cell::nested).collect();
+                write!(f, "({})", values.join(", "))
+            }
+        }
+    }
+}
+
+/// The value bound to a `?` of the VALUES clause: numbers and booleans as they are, the other
+/// scalars as their text, maps as arrays of key-value tuples, which Clickhouse casts to the Map
+/// column. `NULL` is serialised as none, which cannot be bound, see `Cell::literal`.
+impl Serialize for Cell {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Cell::Null => serializer.serialize_none(),
+            Cell::Bool(value) => serializer.serialize_bool(*value),
+            Cell::Int(value) => serializer.serialize_i64(*value),
+            Cell::UInt(value) => serializer.serialize_u64(*value),
+            Cell::Float(value) => serializer.serialize_f64(*value),
+            Cell::Array(values) => serializer.collect_seq(values),
+            Cell::Map(entries) => serializer.collect_seq(entries),
+            Cell::Tuple(values) => {
+                let mut tuple = serializer.serialize_tuple(values.len())?;
+                for value in values {
+                    tuple.serialize_element(value)?;
+                }
+                tuple.end()
+            }
+            other => serializer.collect_str(other),
+        }
+    }
+}
+
+/// A target column, converting the parsed JSON values to its type.
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnType,
+    /// chrono format of date and time values, guessed when not given.
+    format: Option<String>,
+    /// Timezone of the server, for date and time columns without their own.
+    timezone: Tz,
+}
+
+impl Column {
+    pub fn new(name: &str, kind: ColumnType, format: Option<&String>, timezone: Tz) -> Column {
+        Column { name: name.to_owned(), kind, format: format.cloned(), timezone }
+    }
+
+    /// `None` is a missing field, only accepted by Nullable columns.
+    pub fn convert(&self, value: Option<&Value>) -> Result<Cell, String> {
+        match value {
+            Some(value) => self.cell(&self.kind, value),
+            None if matches!(self.kind, ColumnType::Nullable(_)) => Ok(Cell::Null),
+            None => Err(String::from("could not be found.")),
+        }
+    }
+
+    /// Timezone of the server, for date and time types without their own.
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Value inserted when the record is kept despite a missing or invalid field.
+    pub fn default_value(&self) -> Cell {
+        default_value(&self.kind)
+    }
+
+    fn cell(&self, kind: &ColumnType, value: &Value) -> Result<Cell, String> {
+        let invalid = || format!("is not a valid {}: {}", kind, value);
+        let cell = match (kind, value) {
+            (ColumnType::Nullable(_), Value::Null) => Some(Cell::Null),
+            (ColumnType::Nullable(inner) | ColumnType::LowCardinality(inner), _) => return self.cell(inner, value),
+            (_, Value::Null) => return Err(format!("is null but the column ({}) is not Nullable.", kind)),
+            (ColumnType::String | ColumnType::Other(_), _) => Some(Cell::String(text(value))),
+            (ColumnType::FixedString(size), _) => Some(text(value)).filter(|it| it.len() <= *size).map(Cell::String),
+            (ColumnType::Int(bits), _) => integer(value)
+                .filter(|it| *it >= -(1i128 << (bits - 1)) && *it < 1i128 << (bits - 1))
+                .map(|it| Cell::Int(it as i64)),
+            (ColumnType::UInt(bits), _) => integer(value)
+                .filter(|it| *it >= 0 && *it < 1i128 << bits)
+                .map(|it| Cell::UInt(it as u64)),
+            (ColumnType::Float(_), Value::Number(number)) => number.as_f64().map(Cell::Float),
+            (ColumnType::Float(_), Value::String(text)) => text.trim().parse().ok().map(Cell::Float),
+            (ColumnType::Bool, Value::Bool(value)) => Some(Cell::Bool(*value)),
+            (ColumnType::Bool, Value::String(text)) => match text.trim().to_lowercase().as_str() {
+                "true" | "1" => Some(Cell::Bool(true)),
+                "false" | "0" => Some(Cell::Bool(false)),
+                _ => None,
+            },
+            (ColumnType::Bool, Value::Number(_)) => integer(value).filter(|it| *it == 0 || *it == 1).map(|it| Cell::Bool(it == 1)),
+            (ColumnType::Date | ColumnType::Date32, _) => self.date_time(value, None).map(|it| Cell::Date(it.date())),
+            (ColumnType::DateTime(tz), _) => self.date_time(value, *tz).map(|it| Cell::DateTime(it.with_nanosecond(0).unwrap_or(it), 0)),
+            (ColumnType::DateTime64(precision, tz), _) => self.date_time(value, *tz).map(|it| {
+                let unit = 10u32.pow(9u32.saturating_sub(*precision as u32));
+                Cell::DateTime(it.with_nanosecond(it.nanosecond() / unit * unit).unwrap_or(it), (*precision).min(9))
+            }),
+            (ColumnType::Uuid, Value::String(text)) => uuid(text).map(Cell::Uuid),
+            (ColumnType::IPv4, Value::String(text)) => text.trim().parse().ok().map(Cell::IPv4),
+            (ColumnType::IPv4, Value::Number(number)) => number.as_u64()
+                .and_then(|it| u32::try_from(it).ok())
+                .map(|it| Cell::IPv4(Ipv4Addr::from(it))),
+            (ColumnType::IPv6, Value::String(text)) => match text.trim().parse::<Ipv6Addr>() {
+                Ok(address) => Some(Cell::IPv6(address)),
+                Err(_) => text.trim().parse::<Ipv4Addr>().ok().map(|it| Cell::IPv6(it.to_ipv6_mapped())),
+            },
+            (ColumnType::Enum8(values) | ColumnType::Enum16(values), Value::String(text)) => values.iter()
+                .find(|(name, _)| name == text)
+                .map(|(name, value)| Cell::Enum(name.to_owned(), *value)),
+            (ColumnType::Enum8(values) | ColumnType::Enum16(values), Value::Number(_)) => integer(value)
+                .and_then(|number| values.iter().find(|(_, value)| *value as i128 == number))
+                .map(|(name, value)| Cell::Enum(name.to_owned(), *value)),
+            // Text holding JSON, e.g. a field that was serialised twice.
+            (ColumnType::Array(_) | ColumnType::Map(..) | ColumnType::Tuple(_), Value::String(text)) => {
+                return match serde_json::from_str::<Value>(text) {
+                    Ok(parsed @ (Value::Array(_) | Value::Object(_))) => self.cell(kind, &parsed),
+                    _ => Err(invalid()),
+                };
+            }
+            (ColumnType::Array(inner), Value::Array(values)) => Some(Cell::Array(
+                values.iter().map(|it| self.cell(inner, it)).collect::<Result<_, _>>()?)),
+            (ColumnType::Map(key, inner), Value::Object(entries)) => Some(Cell::Map(entries.iter()
+                .map(|(name, it)| Ok((self.cell(key, &Value::String(name.to_owned()))?, self.cell(inner, it)?)))
+                .collect::<Result<_, String>>()?)),
+            (ColumnType::Tuple(elements), Value::Array(values)) if elements.len() == values.len() => Some(Cell::Tuple(
+                elements.iter().zip(values).map(|((_, kind), it)| self.cell(kind, it)).collect::<Result<_, _>>()?)),
+            (ColumnType::Tuple(elements), Value::Object(entries)) if elements.iter().all(|(name, _)| name.is_some()) => {
+                Some(Cell::Tuple(elements.iter()
+                    .map(|(name, kind)| match entries.get(name.as_deref().unwrap_or_default()) {
+                        Some(it) => self.cell(kind, it),
+                        None => self.cell(kind, &Value::Null),
+                    })
+                    .collect::<Result<_, _>>()?))
+            }
+            (ColumnType::Json, Value::Object(_)) => Some(Cell::String(value.to_string())),
+            (ColumnType::Json, Value::String(text)) => serde_json::from_str::<Value>(text).ok()
+                .filter(Value::is_object)
+                .map(|_| Cell::String(text.to_owned())),
+            _ => None,
+        };
+        cell.ok_or_else(invalid)
+    }
+
+    /// Wall-clock time in the timezone `tz` (or the server's): naive times are taken as they are,
+    /// times with an offset and unix timestamps are converted.
+    fn date_time(&self, value: &Value, tz: Option<Tz>) -> Option<NaiveDateTime> {
+        let tz = tz.unwrap_or(self.timezone);
+        let timestamp = |seconds: f64| {
+            DateTime::from_timestamp(seconds.floor() as i64, (seconds.fract() * 1e9) as u32)
+                .map(|it| it.with_timezone(&tz).naive_local())
+        };
+        let text = match value {
+            Value::Number(number) => return number.as_f64().and_then(timestamp),
+            Value::String(text) => text.trim(),
+            _ => return None,
+        };
+        if let Some(format) = &self.format {
+            return NaiveDateTime::parse_from_str(text, format).ok()
+                .or_else(|| DateTime::parse_from_str(text, format).ok().map(|it| it.with_timezone(&tz).naive_local()))
+                .or_else(|| NaiveDate::parse_from_str(text, format).ok().map(|it| it.and_time(NaiveTime::MIN)));
+        }
+        DateTime::parse_from_rfc3339(text).ok().map(|it| it.with_timezone(&tz).naive_local())
+            .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").ok())
+            .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").ok())
+            .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().map(|it| it.and_time(NaiveTime::MIN)))
+            .or_else(|| text.parse().ok().and_then(timestamp))
+    }
+}
+
+fn default_value(kind: &ColumnType) -> Cell {
+    match kind {
+        ColumnType::Nullable(_) => Cell::Null,
+        ColumnType::LowCardinality(inner) => default_value(inner),
+        ColumnType::String | ColumnType::FixedString(_) | ColumnType::Other(_) => Cell::String(String::default()),
+        ColumnType::Int(_) => Cell::Int(0),
+        ColumnType::UInt(_) => Cell::UInt(0),
+        ColumnType::Float(_) => Cell::Float(0.0),
+        ColumnType::Bool => Cell::Bool(false),
+        ColumnType::Date | ColumnType::Date32 => Cell::Date(NaiveDate::default()),
+        ColumnType::DateTime(_) => Cell::DateTime(NaiveDateTime::default(), 0),
+        ColumnType::DateTime64(precision, _) => Cell::DateTime(NaiveDateTime::default(), (*precision).min(9)),
+        ColumnType::Uuid => Cell::Uuid(0),
+        ColumnType::IPv4 => Cell::IPv4(Ipv4Addr::UNSPECIFIED),
+        ColumnType::IPv6 => Cell::IPv6(Ipv6Addr::UNSPECIFIED),
+        ColumnType::Enum8(values) | ColumnType::Enum16(values) => values.first()
+            .map(|(name, value)| Cell::Enum(name.to_owned(), *value))
+            .unwrap_or(Cell::Null),
+        ColumnType::Array(_) => Cell::Array(Vec::new()),
+        ColumnType::Map(..) => Cell::Map(Vec::new()),
+        ColumnType::Tuple(elements) => Cell::Tuple(elements.iter().map(|(_, kind)| default_value(kind)).collect()),
+        ColumnType::Json => Cell::String(String::from("{}")),
+    }
+}
+
+/// Strings as they are, anything else as JSON.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.to_owned(),
+        other => other.to_string(),
+    }
+}
+
+fn integer(value: &Value) -> Option<i128> {
+    match value {
+        Value::Number(number) => number.as_i64().map(i128::from)
+            .or_else(|| number.as_u64().map(i128::from))
+            .or_else(|| number.as_f64().filter(|it| it.fract() == 0.0).map(|it| it as i128)),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn uuid(text: &str) -> Option<u128> {
+    let hex: String = text.trim().chars().filter(|c| *c != '-').collect();
+    match hex.len() {
+        32 => u128::from_str_radix(&hex, 16).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn column(kind: &str) -> Column {
+        Column::new("column", kind.parse().unwrap(), None, Tz::UTC)
+    }
+
+    #[test]
+    fn parse_types() {
+        assert_eq!("Nullable(DateTime64(3, 'Asia/Shanghai'))".parse::<ColumnType>(), Ok(ColumnType::Nullable(
+            Box::new(ColumnType::DateTime64(3, Some(chrono_tz::Asia::Shanghai))))));
+        assert_eq!("LowCardinality(String)".parse::<ColumnType>(), Ok(ColumnType::LowCardinality(Box::new(ColumnType::String))));
+        assert_eq!("Enum8('a, b' = 1, 'it\\'s' = -2)".parse::<ColumnType>(), Ok(ColumnType::Enum8(vec![
+            (String::from("a, b"), 1), (String::from("it's"), -2)])));
+        assert_eq!("Decimal(9, 2)".parse::<ColumnType>(), Ok(ColumnType::Other(String::from("Decimal(9, 2)"))));
+        assert_eq!("DateTime64(9)".parse::<ColumnType>(), Ok(ColumnType::DateTime64(9, None)));
+        assert!("DateTime64(10)".parse::<ColumnType>().is_err());
+        assert!("DateTime64(10, 'UTC')".parse::<ColumnType>().is_err());
+    }
+
+    #[test]
+    fn convert_values() {
+        assert_eq!(column("Int16").convert(Some(&json!("-42"))), Ok(Cell::Int(-42)));
+        assert!(column("UInt8").convert(Some(&json!(256))).is_err());
+        assert_eq!(column("Float64").convert(Some(&json!(1.5))), Ok(Cell::Float(1.5)));
+        assert_eq!(column("String").convert(Some(&json!(7))), Ok(Cell::String(String::from("7"))));
+        assert_eq!(column("Nullable(Int32)").convert(None), Ok(Cell::Null));
+        assert_eq!(column("IPv6").convert(Some(&json!("10.0.0.1"))).unwrap().to_string(), "::ffff:10.0.0.1");
+        assert_eq!(column("UUID").convert(Some(&json!("61F0C404-5CB3-11E7-907B-A6006AD3DBA0"))).unwrap().to_string(),
+                   "61f0c404-5cb3-11e7-907b-a6006ad3dba0");
+        assert_eq!(column("Enum8('info' = 1, 'warn' = 2)").convert(Some(&json!("warn"))), Ok(Cell::Enum(String::from("warn"), 2)));
+        assert_eq!(column("Int32").convert(Some(&json!("abc"))), Err(String::from("is not a valid Int32: \"abc\"")));
+    }
+
+    #[test]
+    fn convert_nested_values() {
+        let kubernetes = json!({"pod_name": "order-7d9f", "labels": {"app": "order"}, "restarts": 2});
+        assert_eq!(column("Map(String, String)").convert(Some(&kubernetes)).unwrap().to_string(),
+                   r#"{'labels': '{"app":"order"}', 'pod_name': 'order-7d9f', 'restarts': '2'}"#);
+        assert_eq!(column("Array(Nullable(UInt8))").convert(Some(&json!("[1, null]"))), Ok(Cell::Array(vec![Cell::UInt(1), Cell::Null])));
+        assert_eq!(column("Tuple(pod String, restarts UInt8)").convert(Some(&json!({"pod": "a", "restarts": 2}))),
+                   Ok(Cell::Tuple(vec![Cell::String(String::from("a")), Cell::UInt(2)])));
+        assert_eq!(column("JSON").convert(Some(&kubernetes)), Ok(Cell::String(kubernetes.to_string())));
+        assert!(column("Array(UInt8)").convert(Some(&json!({"a": 1}))).is_err());
+    }
+
+    #[test]
+    fn convert_date_times() {
+        let time = |kind: &str, value: Value| column(kind).convert(Some(&value)).unwrap().to_string();
+        assert_eq!(time("DateTime64(3)", json!("2024-05-01 10:20:30.123456")), "2024-05-01 10:20:30.123");
+        assert_eq!(time("DateTime('Asia/Shanghai')", json!("2024-05-01T10:20:30Z")), "2024-05-01 18:20:30");
+        assert_eq!(time("DateTime", json!(1714558830)), "2024-05-01 10:20:30");
+        assert_eq!(time("Date", json!("2024-05-01 10:20:30")), "2024-05-01");
+        let formatted = Column::new("date", ColumnType::DateTime(None), Some(&String::from("%d/%m/%Y %H:%M")), Tz::UTC);
+        assert_eq!(formatted.convert(Some(&json!("01/05/2024 10:20"))).unwrap().to_string(), "2024-05-01 10:20:00");
+    }
+}
@@ -1,122 +1,941 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use chrono::{NaiveDateTime};
-use clickhouse::{Client, Row};
-use log::{debug, info};
-use serde::{Serialize};
+use chrono_tz::Tz;
+use clickhouse::{Client, Compression, Row};
+use hyper::{Body, Request};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use indexmap::IndexMap;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+use url::Url;
 
-use crate::{CObject, LogMessage, SendTrait};
+use crate::{LogMessage, Offsets, OffsetStore, SendTrait};
+use crate::config::{InsertFormat, SenderConfig};
+use crate::ddl::{self, TableSchema};
 use crate::error::SyncError;
+use crate::health::{self, Health};
+use crate::metrics::{METRICS, pipeline};
+use crate::policy::{OnError, Outcome};
+use crate::retry::Retry;
+use crate::rowbinary;
+use crate::schema::{Cell, Column};
 
-pub struct Clickhouse {
-    mapping: HashMap<String, String>,
+/// Bookkeeping table with the last offset written to `target` per topic and partition, and
+/// under `intent` the last offset of the batch being written.
+pub struct OffsetTable {
+    ck: Client,
     table: String,
+    target: String,
+    intent: String,
+}
+
+#[derive(Row, Deserialize)]
+struct OffsetRow {
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
+impl OffsetTable {
+    pub async fn init(&self) -> Result<(), SyncError> {
+        self.ck.query(&format!("CREATE TABLE IF NOT EXISTS {} (\
+                target String, topic String, partition Int32, offset Int64, \
+                updated DateTime64(3) DEFAULT now64(3)\
+            ) ENGINE = ReplacingMergeTree(updated) ORDER BY (target, topic, partition)", self.table))
+            .execute().await?;
+        Ok(())
+    }
+
+    pub async fn save(&self, offsets: &Offsets) -> Result<(), SyncError> {
+        self.write(&self.target, offsets).await
+    }
+
+    /// Records the offsets of a batch before its rows, so that it is cut the same way again
+    /// and its inserts carry the same tokens when the rows are written and not the offsets.
+    pub async fn intend(&self, offsets: &Offsets) -> Result<(), SyncError> {
+        self.write(&self.intent, offsets).await
+    }
+
+    async fn write(&self, target: &str, offsets: &Offsets) -> Result<(), SyncError> {
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let params: Vec<&str> = offsets.iter().map(|_| "(?, ?, ?, ?)").collect();
+        let mut handler = self.ck.query(&format!("INSERT INTO {} (target, topic, partition, offset) VALUES {}",
+                                                 self.table, params.join(", ")));
+        for (topic, partition, offset) in offsets.iter() {
+            handler = handler.bind(target).bind(topic).bind(partition).bind(offset);
+        }
+        handler.execute().await?;
+        Ok(())
+    }
+
+    async fn read(&self, target: &str) -> Result<Offsets, SyncError> {
+        let rows = self.ck.query(&format!("SELECT topic, partition, max(offset) FROM {} WHERE target = ? \
+                GROUP BY topic, partition", self.table))
+            .bind(target)
+            .fetch_all::<OffsetRow>().await?;
+        let mut offsets = Offsets::default();
+        for row in rows {
+            offsets.insert(&row.topic, row.partition, row.offset);
+        }
+        Ok(offsets)
+    }
+}
+
+#[async_trait]
+impl OffsetStore for OffsetTable {
+    async fn load(&self) -> Result<Offsets, SyncError> {
+        self.read(&self.target).await
+    }
+
+    async fn intents(&self) -> Result<Offsets, SyncError> {
+        self.read(&self.intent).await
+    }
+}
+
+#[derive(Row, Deserialize)]
+struct ColumnRow {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Insert deduplication token of a batch, derived from the offset range of every partition so
+/// that a batch retried or replayed after a crash is recognised by Clickhouse.
+fn deduplication_token<'a>(table: &str, message: impl IntoIterator<Item=&'a LogMessage>) -> String {
+    let mut ranges: BTreeMap<(&str, i32), (i64, i64)> = BTreeMap::new();
+    for x in message {
+        let range = ranges.entry((&x.topic, x.partition)).or_insert((x.offset, x.offset));
+        *range = (range.0.min(x.offset), range.1.max(x.offset));
+    }
+    let ranges: Vec<String> = ranges.iter()
+        .map(|((topic, partition), (first, last))| format!("{}-{}:{}-{}", topic, partition, first, last))
+        .collect();
+    format!("{}|{}", table, ranges.join(","))
+}
+
+type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+/// Rows of a batch, as they are sent.
+enum Rows {
+    Values(Vec<Vec<Cell>>),
+    /// Encoded rows and their number.
+    RowBinary(Vec<u8>, usize),
+}
+
+impl Rows {
+    fn push(&mut self, columns: &[Column], row: Vec<Cell>) -> Result<(), SyncError> {
+        match self {
+            Rows::Values(rows) => rows.push(row),
+            Rows::RowBinary(body, count) => {
+                let start = body.len();
+                if let Err(e) = rowbinary::encode(columns, &row, body) {
+                    body.truncate(start);
+                    return Err(SyncError::OptionParams(e));
+                }
+                *count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Rows::Values(rows) => rows.len(),
+            Rows::RowBinary(_, count) => *count,
+        }
+    }
+}
+
+/// A target table, with the conversion of the mapped fields to its columns.
+struct Table {
+    name: String,
+    mapping: IndexMap<String, String>,
+    /// Column of the unmapped fields, after the mapped ones.
+    remaining_fields: Option<String>,
     field: String,
-    params: String,
     date_format: HashMap<String, String>,
-    ck: Client,
+    /// Conversion of every mapped field, in the order of `mapping`.
+    columns: Vec<Column>,
+    format: InsertFormat,
+    /// Whether every column can be written as RowBinary when it is the configured format.
+    row_binary: bool,
+    /// Settings of the RowBinary inserts.
+    settings: Vec<(&'static str, &'static str)>,
+    /// Table to create or extend on start, with `create-table`.
+    schema: Option<TableSchema>,
 }
 
-impl Clickhouse {
-    pub fn create(conf: &HashMap<String, CObject>) -> Result<Clickhouse, SyncError> {
-        let conf: Option<HashMap<String, CObject>> = conf.get("sender")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender' could not be found."))?.into();
-        let conf = conf
-            .ok_or(SyncError::MissingParams("Environment variable 'sender' could not be found."))?;
-
-        let mapping: Option<HashMap<String, CObject>> = conf.get("mapping")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.mapping' could not be found."))?.into();
-        let mapping = mapping
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.mapping' could not be found."))?;
-        let mapping: HashMap<String, String> = mapping.iter().map(|(key, value)| (key.to_owned(), value.into())).collect();
-
-        let date_format: Option<HashMap<String, CObject>> = conf.get("date-format")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.date-format' could not be found."))?.into();
-        let date_format = date_format.unwrap_or(HashMap::default());
-        let date_format: HashMap<String, String> = date_format.iter().map(|(key, value)| (key.to_owned(), value.into())).collect();
-
-        let conf: Option<HashMap<String, CObject>> = conf.get("clickhouse")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse' could not be found."))?.into();
-        let clickhouse = conf
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse' could not be found."))?;
-        let database: String = clickhouse.get("database")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse.database' could not be found."))?.into();
-        let table: String = clickhouse.get("table")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse.table' could not be found."))?.into();
-        let server: String = clickhouse.get("server")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse.server' could not be found."))?.into();
-        let username: String = clickhouse.get("username")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse.username' could not be found."))?.into();
-        let password: String = clickhouse.get("password")
-            .ok_or(SyncError::MissingParams("Environment variable 'sender.clickhouse.password' could not be found."))?.into();
-
-        let field: Vec<String> = mapping.iter().map(|(key, _value)| key.to_owned()).collect();
-        let field_count = mapping.len();
-        Ok(Clickhouse {
-            mapping,
-            table,
+impl Table {
+    /// Until the schema is loaded, the columns have the types the table would be created with.
+    fn create(conf: &SenderConfig, name: &str) -> Result<Table, SyncError> {
+        let mut conf = conf.clone();
+        conf.clickhouse.table = name.to_owned();
+        let field: Vec<String> = conf.mapping.keys().chain(&conf.remaining_fields).map(|key| key.to_owned()).collect();
+        let mut columns = Vec::with_capacity(field.len());
+        for key in &field {
+            columns.push(Column::new(key, ddl::column_type(&conf, key)?, conf.date_format.get(key), Tz::UTC));
+        }
+        Ok(Table {
+            name: name.to_owned(),
+            mapping: conf.mapping.clone(),
+            remaining_fields: conf.remaining_fields.clone(),
             field: field.join(", "),
-            params: format!("({})", std::iter::repeat(String::from("?")).take(field_count).collect::<Vec<String>>().join(", ")),
-            date_format,
-            ck: Client::default()
-                .with_url(server)
-                .with_user(username)
-                .with_password(password)
-                .with_database(database),
-
+            date_format: conf.date_format.clone(),
+            columns,
+            format: conf.clickhouse.format,
+            row_binary: conf.clickhouse.format == InsertFormat::RowBinary,
+            settings: Vec::new(),
+            schema: match conf.clickhouse.create_table {
+                Some(_) => Some(TableSchema::create(&conf)?),
+                None => None,
+            },
         })
     }
+
+    /// Uses the column types of `types`, values are converted to them.
+    fn load(&mut self, types: &HashMap<String, String>, timezone: Tz) -> Result<(), SyncError> {
+        if types.is_empty() {
+            return Err(SyncError::OptionParams(format!("Table '{}' does not exist.", self.name)));
+        }
+        let mut columns = Vec::with_capacity(self.mapping.len() + 1);
+        for key in self.mapping.keys().chain(&self.remaining_fields) {
+            let kind = types.get(key)
+                .ok_or_else(|| SyncError::OptionParams(format!("Column '{}' does not exist in table '{}'.", key, self.name)))?
+                .parse().map_err(SyncError::OptionParams)?;
+            columns.push(Column::new(key, kind, self.date_format.get(key), timezone));
+        }
+        let described: Vec<String> = columns.iter().map(|it| format!("{} {}", it.name, it.kind)).collect();
+        info!("[Clickhouse] Table {} ({})", self.name, described.join(", "));
+        self.row_binary = self.format == InsertFormat::RowBinary
+            && columns.iter().all(|it| rowbinary::encodable(&it.kind));
+        if self.format == InsertFormat::RowBinary && !self.row_binary {
+            warn!("[Clickhouse] Some column types of {} cannot be written as RowBinary, rows are inserted with VALUES", self.name);
+        }
+        self.settings = columns.iter().flat_map(|it| rowbinary::settings(&it.kind)).collect();
+        self.settings.dedup();
+        self.columns = columns;
+        Ok(())
+    }
+
+    fn rows(&self) -> Rows {
+        match self.row_binary {
+            true => Rows::RowBinary(Vec::new(), 0),
+            false => Rows::Values(Vec::new()),
+        }
+    }
+
+    /// With `lenient`, missing fields are bound as empty values instead of failing.
+    fn values(&self, message: &LogMessage, lenient: bool) -> Result<Vec<Cell>, SyncError> {
+        let empty = Map::new();
+        let data_item = match &message.map {
+            Some(map) => map,
+            None if lenient => &empty,
+            None => return Err(SyncError::MissingParams("Message has not been parsed.")),
+        };
+        let mut row = Vec::with_capacity(self.columns.len());
+        for ((key, data_key), column) in self.mapping.iter().zip(&self.columns) {
+            match column.convert(message.field(data_key).as_deref()) {
+                Ok(cell) => row.push(cell),
+                Err(_) if lenient => row.push(column.default_value()),
+                Err(e) => return Err(SyncError::OptionParams(format!("Field '{}' mapped to column '{}' {}", data_key, key, e))),
+            }
+        }
+        if let Some(column) = self.columns.get(self.mapping.len()) {
+            // The raw log line has been parsed into fields already.
+            let remaining: Map<String, Value> = data_item.iter()
+                .filter(|(key, _)| *key != "log" && !self.mapping.values().any(|it| it == *key))
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect();
+            match column.convert(Some(&Value::Object(remaining))) {
+                Ok(cell) => row.push(cell),
+                Err(_) if lenient => row.push(column.default_value()),
+                Err(e) => return Err(SyncError::OptionParams(format!("Remaining fields of column '{}' {}", column.name, e))),
+            }
+        }
+        Ok(row)
+    }
+}
+
+/// Records of a topic and/or with given field values, written to the table named by `table`.
+struct Route {
+    topic: Option<String>,
+    when: IndexMap<String, String>,
+    /// Table name, possibly with `${field}` placeholders.
+    table: String,
+    conf: SenderConfig,
+}
+
+impl Route {
+    fn matches(&self, message: &LogMessage) -> bool {
+        self.topic.as_ref().is_none_or(|it| *it == message.topic)
+            && self.when.iter().all(|(field, value)| message.field(field).as_deref().map(text).as_deref() == Some(value.as_str()))
+    }
+
+    /// Name of the table of a record.
+    fn table(&self, message: &LogMessage) -> Result<String, SyncError> {
+        let mut name = String::with_capacity(self.table.len());
+        let mut rest = self.table.as_str();
+        while let Some(start) = rest.find("${") {
+            let end = start + rest[start..].find('}')
+                .ok_or_else(|| SyncError::OptionParams(format!("Table '{}' has an unterminated placeholder.", self.table)))?;
+            let field = &rest[start + 2..end];
+            let value = message.field(field).as_deref().map(text)
+                .ok_or_else(|| SyncError::OptionParams(format!("Field '{}' of table '{}' could not be found.", field, self.table)))?;
+            name.push_str(&rest[..start]);
+            name.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+        if table_name(&name).is_none() {
+            return Err(SyncError::OptionParams(format!("'{}' is not a valid table name for '{}'.", name, self.table)));
+        }
+        Ok(name)
+    }
+
+    fn is_static(&self) -> bool {
+        !self.table.contains("${")
+    }
 }
 
+/// Database, when it is named, and table of a `[database.]table` name. Both end up in SQL
+/// statements, they must be plain identifiers.
+fn table_name(name: &str) -> Option<(Option<&str>, &str)> {
+    let identifier = |it: &str| !it.is_empty() && it.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match name.split_once('.') {
+        Some((database, table)) => (identifier(database) && identifier(table)).then_some((Some(database), table)),
+        None => identifier(name).then_some((None, name)),
+    }
+}
 
-#[derive(Row, Serialize, Debug)]
-struct Log {
-    date: String,
-    system_code: String,
-    env: String,
-    service_code: String,
-    trace_id: String,
-    level: String,
-    thread: String,
-    class: String,
-    message: String,
+/// Text of a field, as matched by routes and inserted in table names.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.to_owned(),
+        value => value.to_string(),
+    }
 }
 
-#[async_trait(? Send)]
-impl SendTrait for Clickhouse {
-    async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
-        if message.is_empty() {
-            return Ok(());
+/// Records of a push written to the same table.
+struct Batch<'a> {
+    table: Arc<Table>,
+    rows: Rows,
+    messages: Vec<&'a LogMessage>,
+}
+
+pub struct Clickhouse {
+    /// `sender.routes` followed by the route of the other records.
+    routes: Vec<Route>,
+    /// Tables by name, those of the routes without placeholders from the start, the others
+    /// once they have been routed to.
+    tables: RwLock<HashMap<String, Arc<Table>>>,
+    ck: Client,
+    /// Shared with `ck`, for the inserts it cannot stream.
+    http: HttpClient,
+    server: String,
+    database: String,
+    username: String,
+    password: String,
+    /// Of the inserts, those of `ck` and the RowBinary ones alike.
+    compression: Compression,
+    offsets: Option<Arc<OffsetTable>>,
+    on_error: OnError,
+    retry: Retry,
+    /// Told whether the server answered the last insert, under `name`.
+    health: Arc<Health>,
+    /// Of the sink in the health of its pipeline, its default table and its server.
+    name: String,
+}
+
+impl Clickhouse {
+    pub fn create(conf: &SenderConfig, on_error: OnError) -> Result<Clickhouse, SyncError> {
+        let clickhouse = &conf.clickhouse;
+        // Same settings as the default client of the clickhouse crate.
+        let mut connector = HttpConnector::new();
+        connector.set_keepalive(Some(Duration::from_secs(60)));
+        connector.enforce_http(false);
+        let http: HttpClient = hyper::Client::builder()
+            .pool_idle_timeout(Duration::from_secs(2))
+            .build(HttpsConnector::new_with_connector(connector));
+        let compression = Compression::default();
+        let ck = Client::with_http_client(http.clone())
+            .with_compression(compression)
+            .with_url(&clickhouse.server)
+            .with_user(&clickhouse.username)
+            .with_password(&clickhouse.password)
+            .with_database(&clickhouse.database);
+        let offsets = match clickhouse.exactly_once {
+            true => Some(Arc::new(OffsetTable {
+                ck: ck.clone(),
+                table: clickhouse.offsets_table.to_owned(),
+                target: format!("{}.{}", clickhouse.database, clickhouse.table),
+                // Rows of their own, replaced independently of the written offsets.
+                intent: format!("{}.{}#intent", clickhouse.database, clickhouse.table),
+            })),
+            false => None,
+        };
+        let mut routes: Vec<Route> = conf.routes.iter()
+            .map(|route| Route {
+                topic: route.topic.clone(),
+                when: route.when.clone(),
+                table: route.table.to_owned(),
+                conf: conf.route(route),
+            })
+            .collect();
+        routes.push(Route {
+            topic: None,
+            when: IndexMap::new(),
+            table: clickhouse.table.to_owned(),
+            conf: SenderConfig { routes: Vec::new(), ..conf.clone() },
+        });
+        let mut tables = HashMap::new();
+        for route in routes.iter().filter(|it| it.is_static()) {
+            tables.entry(route.table.to_owned())
+                .or_insert(Arc::new(Table::create(&route.conf, &route.table)?));
         }
-        let params: Vec<String> = message.iter().map(|_x| self.params.to_owned()).collect();
-        let sql = format!("INSERT INTO {} ({}) VALUES", &self.table, self.field);
-        debug!("[Clickhouse] {} ==> {} ...", message.len(), sql);
-        let mut handler = self.ck.query(&format!("{}\n{}", sql, params.join(", ")));
-        for x in &message {
-            let data_item = x.map.clone().ok_or(SyncError::Option)?;
-            for (key, data_key) in &self.mapping {
-                let value = data_item.get(data_key).ok_or(SyncError::Option)?
-                    .as_str().unwrap_or("").to_owned();
-                // format date
-                if self.date_format.contains_key(key) {
-                    match self.date_format.get(key) {
-                        Some(format) => {
-                            handler = handler.bind(NaiveDateTime::parse_from_str(&value, format)
-                                .unwrap_or(NaiveDateTime::default())
-                                .format("%Y-%m-%d %H:%M:%S").to_string());
-                            continue;
-                        }
-                        None => {}
+        Ok(Clickhouse {
+            routes,
+            tables: RwLock::new(tables),
+            ck,
+            http,
+            server: clickhouse.server.to_owned(),
+            database: clickhouse.database.to_owned(),
+            username: clickhouse.username.to_owned(),
+            password: clickhouse.password.to_owned(),
+            compression,
+            offsets,
+            on_error,
+            retry: Retry::new(&clickhouse.retry),
+            health: health::current(),
+            name: format!("{}.{}@{}", clickhouse.database, clickhouse.table, clickhouse.server),
+        })
+    }
+
+    /// Prepares the Clickhouse side, the only part of the sink that needs the network.
+    pub async fn init(&self) -> Result<(), SyncError> {
+        let result = self.prepare().await;
+        self.health.sink(&self.name, result.is_ok());
+        result
+    }
+
+    async fn prepare(&self) -> Result<(), SyncError> {
+        self.open_static(true).await?;
+        if let Some(offsets) = &self.offsets {
+            offsets.init().await?;
+            info!("[Clickhouse] Exactly-once delivery, offsets are recorded in {}", offsets.table);
+        }
+        Ok(())
+    }
+
+    /// Uses the column types of `schema`, table name => column name => type, for the tables of
+    /// the routes without placeholders instead of reading them from Clickhouse. Date and time
+    /// types without a timezone of their own are taken as UTC.
+    pub fn load_types(&self, schema: &HashMap<String, HashMap<String, String>>) -> Result<(), SyncError> {
+        let mut opened = HashSet::new();
+        for route in self.routes.iter().filter(|it| it.is_static() && opened.insert(&it.table)) {
+            let Some(types) = schema.get(&route.table) else {
+                continue;
+            };
+            let mut table = Table::create(&route.conf, &route.table)?;
+            table.load(types, Tz::UTC)?;
+            self.tables.write().unwrap().insert(route.table.to_owned(), Arc::new(table));
+        }
+        Ok(())
+    }
+
+    /// Loads the tables of the routes without placeholders, with `migrate` after creating or
+    /// extending them.
+    async fn open_static(&self, migrate: bool) -> Result<(), SyncError> {
+        let mut opened = HashSet::new();
+        for route in self.routes.iter().filter(|it| it.is_static() && opened.insert(&it.table)) {
+            let table = self.open(&route.conf, &route.table, migrate).await?;
+            self.tables.write().unwrap().insert(route.table.to_owned(), Arc::new(table));
+        }
+        Ok(())
+    }
+
+    async fn open(&self, conf: &SenderConfig, name: &str, migrate: bool) -> Result<Table, SyncError> {
+        let mut table = Table::create(conf, name)?;
+        let timezone: String = self.ck.query("SELECT timezone()").fetch_one().await?;
+        let timezone: Tz = timezone.parse()
+            .map_err(|_| SyncError::OptionParams(format!("Unknown server timezone '{}'.", timezone)))?;
+        let mut types = self.table_columns(name).await?;
+        if let Some(schema) = table.schema.as_ref().filter(|_| migrate) {
+            if self.migrate(schema, &types).await? {
+                types = self.table_columns(name).await?;
+            }
+        }
+        table.load(&types, timezone)?;
+        Ok(table)
+    }
+
+    /// Column name => type of a table, empty when it does not exist.
+    async fn table_columns(&self, name: &str) -> Result<HashMap<String, String>, SyncError> {
+        let (database, table) = table_name(name)
+            .ok_or_else(|| SyncError::OptionParams(format!("'{}' is not a valid table name.", name)))?;
+        let rows = self.ck.query("SELECT name, type FROM system.columns WHERE database = ? AND table = ?")
+            .bind(database.unwrap_or(&self.database))
+            .bind(table)
+            .fetch_all::<ColumnRow>().await?;
+        Ok(rows.into_iter().map(|row| (row.name, row.kind)).collect())
+    }
+
+    /// Creates the table when it does not exist, otherwise adds the mapped columns it lacks.
+    /// Columns are never altered nor dropped. `true` when a statement has been executed.
+    async fn migrate(&self, schema: &TableSchema, types: &HashMap<String, String>) -> Result<bool, SyncError> {
+        let statements = match types.is_empty() {
+            true => vec![schema.create_table()],
+            false => schema.add_columns(|name| types.contains_key(name)),
+        };
+        for sql in &statements {
+            info!("[Clickhouse] {}", sql.replace('\n', " "));
+            self.ck.query(sql).execute().await?;
+        }
+        Ok(!statements.is_empty())
+    }
+
+    /// Offsets recorded by the sink in exactly-once mode.
+    pub fn offset_store(&self) -> Option<Arc<dyn OffsetStore>> {
+        self.offsets.clone().map(|it| it as Arc<dyn OffsetStore>)
+    }
+
+    /// The route of a record and the name of its table.
+    fn route(&self, message: &LogMessage) -> Result<(&Route, String), SyncError> {
+        if message.map.is_none() {
+            return Err(SyncError::MissingParams("Message has not been parsed."));
+        }
+        let route = self.routes.iter()
+            .find(|it| it.matches(message))
+            .ok_or(SyncError::Option)?;
+        Ok((route, route.table(message)?))
+    }
+
+    /// Column name => field name of the table a record is routed to.
+    pub fn mapping(&self, message: &LogMessage) -> Result<&IndexMap<String, String>, SyncError> {
+        Ok(&self.route(message)?.0.conf.mapping)
+    }
+
+    /// Target table and columns with the values of one message, before the tables routed to
+    /// with a placeholder are known, their columns have the types they would be created with.
+    pub fn row(&self, message: &LogMessage) -> Result<(String, Vec<(String, Cell)>), SyncError> {
+        let (route, name) = self.route(message)?;
+        let cached = self.tables.read().unwrap().get(&name).cloned();
+        let table = match cached {
+            Some(table) => table,
+            None => Arc::new(Table::create(&route.conf, &name)?),
+        };
+        let row = table.values(message, false)?;
+        Ok((name, table.columns.iter().map(|it| it.name.to_owned()).zip(row).collect()))
+    }
+
+    /// Table of a name, opened on first use.
+    async fn table(&self, route: &Route, name: &str) -> Result<Arc<Table>, SyncError> {
+        if let Some(table) = self.tables.read().unwrap().get(name) {
+            return Ok(table.clone());
+        }
+        let table = Arc::new(self.retry.run("Clickhouse", || self.open(&route.conf, name, true)).await?);
+        info!("[Clickhouse] Records of route '{}' are now also written to {}", route.table, name);
+        self.tables.write().unwrap().insert(name.to_owned(), table.clone());
+        Ok(table)
+    }
+
+    /// Adds a record to the batch of its table, with `lenient` to the batch of the default table.
+    /// Tables that could not be opened are in `unavailable` with the reason, so that the other
+    /// records of the push routed to them are rejected without looking them up again.
+    async fn add<'a>(&self, batches: &mut IndexMap<String, Batch<'a>>, unavailable: &mut HashMap<String, String>,
+                     message: &'a LogMessage, lenient: bool) -> Result<(), SyncError> {
+        let (route, name) = match lenient {
+            true => {
+                let route = self.routes.last().ok_or(SyncError::Option)?;
+                (route, route.table.to_owned())
+            }
+            false => self.route(message)?,
+        };
+        let batch = match batches.get_mut(&name) {
+            Some(batch) => batch,
+            None => {
+                if let Some(reason) = unavailable.get(&name) {
+                    return Err(SyncError::OptionParams(reason.to_owned()));
+                }
+                let table = match self.table(route, &name).await {
+                    Ok(table) => table,
+                    Err(e) => {
+                        unavailable.insert(name, e.to_string());
+                        return Err(e);
                     }
+                };
+                batches.entry(name).or_insert(Batch { rows: table.rows(), table, messages: Vec::new() })
+            }
+        };
+        batch.rows.push(&batch.table.columns, batch.table.values(message, lenient)?)?;
+        batch.messages.push(message);
+        Ok(())
+    }
+
+    /// Writes the rows of every table, and the offsets of the batch in exactly-once mode.
+    /// Retried inserts of the same batch carry the same deduplication tokens, and skip the
+    /// `written` first tables an earlier attempt inserted.
+    ///
+    /// In exactly-once mode the offsets of the batch are recorded as an intent first: after a
+    /// crash between the rows and the offsets, the source cuts the batch the same way again and
+    /// Clickhouse drops its rows as already inserted.
+    async fn insert(&self, batches: &IndexMap<String, Batch<'_>>, message: &[LogMessage], written: &AtomicUsize) -> Result<(), SyncError> {
+        if let Some(offsets) = &self.offsets {
+            offsets.intend(&Offsets::from(message)).await?;
+        }
+        for batch in batches.values().skip(written.load(Ordering::Relaxed)) {
+            let table = &batch.table;
+            let token = deduplication_token(&table.name, batch.messages.iter().copied());
+            let _timer = METRICS.insert_duration.with_label_values(&[&pipeline(), &table.name]).start_timer();
+            match &batch.rows {
+                Rows::Values(rows) if !rows.is_empty() => self.insert_values(table, rows, token).await?,
+                Rows::RowBinary(body, count) if *count > 0 => {
+                    let sql = format!("INSERT INTO {} ({}) FORMAT RowBinary", table.name, table.field);
+                    debug!("[Clickhouse] {} ==> {} ...", count, sql);
+                    self.post(&sql, body.to_owned(), &token, &table.settings).await?;
                 }
-                handler = handler.bind(value);
+                _ => {}
             }
+            written.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(offsets) = &self.offsets {
+            offsets.save(&Offsets::from(message)).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_values(&self, table: &Table, rows: &[Vec<Cell>], token: String) -> Result<(), SyncError> {
+        let sql = format!("INSERT INTO {} ({}) VALUES", table.name, table.field);
+        debug!("[Clickhouse] {} ==> {} ...", rows.len(), sql);
+        let (params, bound) = values(rows);
+        let client = self.ck.clone().with_option("insert_deduplication_token", token);
+        let mut handler = client.query(&format!("{}\n{}", sql, params));
+        for value in bound {
+            handler = handler.bind(value);
         }
         handler.execute().await?;
-        let last = message.last().ok_or(SyncError::Option)?;
-        info!("[Clickhouse] {} data pushes have been completed, and the current kafka offset value is {}",
-            &message.len(), last.offset);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Sends `body` as the data of `query`, which the HTTP interface expects in the URL, the
+    /// way the clickhouse crate sends its inserts. Its `Insert` cannot be used: it names the
+    /// columns after the compile-time `Row::COLUMN_NAMES` while these come from the mapping,
+    /// and it drops the client options, i.e. the deduplication token and the settings of the
+    /// column types.
+    async fn post(&self, query: &str, body: Vec<u8>, token: &str, settings: &[(&str, &str)]) -> Result<(), SyncError> {
+        use clickhouse::error::Error;
+        let compressed = self.compression != Compression::None;
+        let body = match compressed {
+            true => rowbinary::compress(&body, self.compression)?,
+            false => body,
+        };
+        let mut url = Url::parse(&self.server).map_err(|e| Error::InvalidParams(Box::new(e)))?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.append_pair("database", &self.database)
+                .append_pair("query", query)
+                .append_pair("insert_deduplication_token", token)
+                .extend_pairs(settings);
+            if compressed {
+                pairs.append_pair("decompress", "1");
+            }
+        }
+        let request = Request::post(url.as_str())
+            .header("X-ClickHouse-User", &self.username)
+            .header("X-ClickHouse-Key", &self.password)
+            .body(Body::from(body))
+            .map_err(|e| Error::InvalidParams(Box::new(e)))?;
+        let response = self.http.request(request).await.map_err(Error::from)?;
+        let status = response.status();
+        let content = hyper::body::to_bytes(response.into_body()).await.map_err(Error::from)?;
+        if !status.is_success() {
+            // Same message as the clickhouse crate, so that errors are classified alike.
+            let reason = match String::from_utf8_lossy(&content).trim() {
+                "" => format!("{} {}", status.as_str(), status.canonical_reason().unwrap_or("<unknown>")),
+                text => text.to_owned(),
+            };
+            return Err(Error::BadResponse(reason).into());
+        }
+        Ok(())
+    }
+}
+
+/// The rows of a VALUES clause with a parameter for each value, and the values to bind. The
+/// values that are or hold a NULL cannot be bound, they are written out in place of the parameter.
+fn values(rows: &[Vec<Cell>]) -> (String, Vec<&Cell>) {
+    let mut bound = Vec::new();
+    let params: Vec<String> = rows.iter()
+        .map(|row| {
+            let values: Vec<String> = row.iter()
+                .map(|value| match value.has_null() {
+                    true => value.literal(),
+                    false => {
+                        bound.push(value);
+                        String::from("?")
+                    }
+                })
+                .collect();
+            format!("({})", values.join(", "))
+        })
+        .collect();
+    (params.join(", "), bound)
+}
+
+#[async_trait]
+impl SendTrait for Clickhouse {
+    async fn push(&self, message: Vec<LogMessage>) -> Result<(), SyncError> {
+        // In the order the tables are first routed to.
+        let mut batches: IndexMap<String, Batch> = IndexMap::new();
+        let mut unavailable = HashMap::new();
+        for x in &message {
+            let Err(e) = self.add(&mut batches, &mut unavailable, x, false).await else {
+                continue;
+            };
+            match self.on_error.reject(x, e).await? {
+                Outcome::Drop => continue,
+                Outcome::Default => self.add(&mut batches, &mut unavailable, x, true).await?,
+            }
+        }
+        let written = AtomicUsize::new(0);
+        self.retry.run("Clickhouse", || async {
+            let result = self.insert(&batches, &message, &written).await;
+            self.health.sink(&self.name, result.is_ok());
+            result
+        }).await?;
+        for (name, batch) in &batches {
+            METRICS.inserted.with_label_values(&[&pipeline(), name]).inc_by(batch.rows.len() as u64);
+        }
+        if let Some(last) = message.last() {
+            let rows: usize = batches.values().map(|it| it.rows.len()).sum();
+            info!("[Clickhouse] {} data pushes have been completed to {} table(s), and the current kafka offset value is {}",
+                rows, batches.len(), last.offset);
+        }
+        Ok(())
+    }
+
+    /// `true` while inserts keep failing for a transient reason.
+    fn circuit(&self) -> Option<watch::Receiver<bool>> {
+        Some(self.retry.circuit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use serde_json::json;
+
+    use crate::source::Batcher;
+
+    use super::*;
+
+    fn message(partition: i32, offset: i64) -> LogMessage {
+        LogMessage::new("logs", partition, offset, "")
+    }
+
+    #[test]
+    fn route_by_topic_field_and_template() -> Result<(), SyncError> {
+        let conf = crate::config::Log2ClickConfig::from_yaml(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group_id: log2click
+    username: admin
+    password: admin
+parser:
+  regex: '(\S+) (\S+) (.*)'
+  mapping: env, level, message
+sender:
+  mapping:
+    message: message
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+  routes:
+    - topic: audit
+      table: audit
+      mapping:
+        level: level
+        message: message
+        topic: _kafka.topic
+    - when:
+        level: ERROR
+      table: errors_${env}
+"#)?;
+        let sink = Clickhouse::create(&conf.pipelines[0].sender, OnError::fail(crate::policy::Stage::Sink))?;
+        let route = |topic: &str, fields: Value| {
+            let message = LogMessage { topic: topic.to_owned(), map: fields.as_object().cloned(), ..message(0, 0) };
+            sink.row(&message).map(|(table, row)| (table, row.len()))
+        };
+        assert_eq!(route("audit", json!({"env": "prod", "level": "ERROR", "message": "m"}))?, (String::from("audit"), 3));
+        assert_eq!(route("logs", json!({"env": "prod", "level": "ERROR", "message": "m"}))?, (String::from("errors_prod"), 1));
+        assert_eq!(route("logs", json!({"env": "prod", "level": "INFO", "message": "m"}))?, (String::from("app"), 1));
+        assert!(route("logs", json!({"env": "a;b", "level": "ERROR", "message": "m"})).is_err());
+        let message = LogMessage { topic: String::from("audit"), map: json!({"level": "INFO", "message": "m"}).as_object().cloned(), ..message(0, 0) };
+        assert_eq!(sink.row(&message)?.1.last(), Some(&(String::from("topic"), Cell::String(String::from("audit")))));
+        Ok(())
+    }
+
+    #[test]
+    fn types_of_a_schema_file() -> Result<(), SyncError> {
+        let conf = crate::config::Log2ClickConfig::from_yaml(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group_id: log2click
+    username: admin
+    password: admin
+parser:
+  regex: '(\S+) (.*)'
+  mapping: status, message
+sender:
+  mapping:
+    status: status
+    message: message
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+"#)?;
+        let sink = Clickhouse::create(&conf.pipelines[0].sender, OnError::fail(crate::policy::Stage::Sink))?;
+        let message = LogMessage { map: json!({"status": "404", "message": "m"}).as_object().cloned(), ..message(0, 0) };
+        assert_eq!(sink.row(&message)?.1[0], (String::from("status"), Cell::String(String::from("404"))));
+        let types = HashMap::from([(String::from("status"), String::from("UInt16")), (String::from("message"), String::from("String"))]);
+        sink.load_types(&HashMap::from([(String::from("app"), types)]))?;
+        assert_eq!(sink.row(&message)?.1[0], (String::from("status"), Cell::UInt(404)));
+        // Every mapped column must be described.
+        let types = HashMap::from([(String::from("status"), String::from("UInt16"))]);
+        assert!(sink.load_types(&HashMap::from([(String::from("app"), types)])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn nested_nulls_are_written_out() {
+        let rows = vec![
+            vec![Cell::Int(1), Cell::Null, Cell::Array(vec![Cell::String(String::from("a?")), Cell::Null])],
+            vec![Cell::Int(2), Cell::String(String::from("b")), Cell::Map(vec![(Cell::String(String::from("k")), Cell::Null)])],
+        ];
+        let (params, bound) = values(&rows);
+        assert_eq!(params, r"(?, NULL, ['a\x3F', NULL]), (?, ?, [('k', NULL)])");
+        assert_eq!(bound, vec![&Cell::Int(1), &Cell::Int(2), &Cell::String(String::from("b"))]);
+    }
+
+    #[test]
+    fn table_names() {
+        assert_eq!(table_name("app"), Some((None, "app")));
+        assert_eq!(table_name("archive.app_2024"), Some((Some("archive"), "app_2024")));
+        for name in ["", "a.b.c", ".app", "archive.", "app;DROP", "app name"] {
+            assert_eq!(table_name(name), None, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn crash_between_rows_and_offsets() -> Result<(), SyncError> {
+        let messages: Vec<LogMessage> = (0..10).map(|offset| message(offset as i32 % 2, offset / 2)).collect();
+        // Clickhouse, which drops the inserts of a token it has already seen.
+        let mut tokens = HashSet::new();
+        let mut rows = Vec::new();
+        let mut write = |batch: &[LogMessage]| {
+            if tokens.insert(deduplication_token("app", batch)) {
+                rows.extend(batch.iter().map(|it| (it.partition, it.offset)));
+            }
+        };
+        // Batches of 4: the first one is written with its offsets, the second one only has its
+        // intent and rows written.
+        let batcher = Batcher::new(stream::iter(messages.clone().into_iter().map(Ok)), 4, 60_000, Default::default());
+        let first = batcher.next().await?;
+        write(&first);
+        let stored = Offsets::from(first.as_slice());
+        let second = batcher.next().await?;
+        write(&second);
+        let intent = Offsets::from(second.as_slice());
+
+        // Resumed with batches of 3 after the stored offsets.
+        let resumed: Vec<Result<LogMessage, SyncError>> = messages.into_iter()
+            .filter(|it| stored.get(&it.topic, it.partition).is_none_or(|offset| it.offset > offset))
+            .map(Ok)
+            .collect();
+        let batcher = Batcher::new(stream::iter(resumed), 3, 60_000, Arc::new(std::sync::Mutex::new(intent)));
+        loop {
+            let batch = batcher.next().await?;
+            if batch.is_empty() {
+                break;
+            }
+            write(&batch);
+        }
+        rows.sort();
+        let expected: Vec<(i32, i64)> = (0..2).flat_map(|partition| (0..5).map(move |offset| (partition, offset))).collect();
+        assert_eq!(rows, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn deduplication_token_covers_every_partition() {
+        let batch = [message(1, 9), message(0, 4), message(1, 7), message(0, 6)];
+        assert_eq!(deduplication_token("app", &batch), "app|logs-0:4-6,logs-1:7-9");
+    }
+
+    #[derive(Debug, PartialEq, Row, Deserialize)]
+    struct Logged {
+        level: String,
+        message: String,
+        kafka_offset: u64,
+    }
+
+    /// `CLICKHOUSE_URL=http://127.0.0.1:8123 cargo test -- --ignored`, the table is dropped first.
+    #[tokio::test]
+    #[ignore = "needs a Clickhouse server at CLICKHOUSE_URL"]
+    async fn row_binary_round_trip() -> Result<(), SyncError> {
+        let server = std::env::var("CLICKHOUSE_URL").unwrap_or_else(|_| String::from("http://127.0.0.1:8123"));
+        let conf = crate::config::Log2ClickConfig::from_yaml(&format!(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9092
+    topic: logs
+    group-id: log2click
+parser:
+  regex: '(\S+) (.*)'
+  mapping: level, message
+sender:
+  mapping:
+    level: level
+    message: message
+    kafka_offset: _kafka.offset
+  clickhouse:
+    server: {}
+    database: default
+    table: log2click_round_trip
+    create-table:
+      types:
+        kafka_offset: UInt64
+"#, server))?;
+        let sink = Clickhouse::create(&conf.pipelines[0].sender, OnError::fail(crate::policy::Stage::Sink))?;
+        sink.ck.query("DROP TABLE IF EXISTS log2click_round_trip").execute().await?;
+        sink.init().await?;
+        // Enough rows for several compressed blocks.
+        let batch: Vec<LogMessage> = (0..5000)
+            .map(|offset| LogMessage {
+                map: json!({"level": "INFO", "message": format!("{:0>100}", offset)}).as_object().cloned(),
+                ..message(0, offset)
+            })
+            .collect();
+        sink.push(batch).await?;
+        let logged = sink.ck.query("SELECT level, message, kafka_offset FROM log2click_round_trip ORDER BY kafka_offset")
+            .fetch_all::<Logged>().await?;
+        let expected: Vec<Logged> = (0..5000)
+            .map(|offset| Logged { level: String::from("INFO"), message: format!("{:0>100}", offset), kafka_offset: offset as u64 })
+            .collect();
+        assert_eq!(logged, expected);
+        Ok(())
+    }
+}
@@ -1,15 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use async_trait::async_trait;
-use log::{debug, info, warn};
-use rdkafka::{ClientConfig, Message};
-use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
-use rdkafka::message::BorrowedMessage;
-use tokio::time;
+use futures::{Stream, stream};
+use log::{debug, error, info, warn};
+use rdkafka::{ClientConfig, ClientContext, Message, Offset, TopicPartitionList};
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::message::{BorrowedMessage, Headers};
+use rdkafka::statistics::Statistics;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::{Mutex, watch};
+use tokio::time::{Instant, timeout_at};
+use tokio_stream::StreamExt;
 
-use crate::{CObject, LogMessage, ReceiveTrait};
+use crate::{LogMessage, Offsets, OffsetStore, ReceiveTrait};
+use crate::config::{KafkaConfig, StartFrom};
 use crate::error::SyncError;
+use crate::health::{self, Health};
+use crate::metrics::{METRICS, pipeline};
 
 impl From<BorrowedMessage<'_>> for LogMessage {
     fn from(message: BorrowedMessage) -> Self {
@@ -22,104 +34,707 @@ impl From<BorrowedMessage<'_>> for LogMessage {
             }
         };
 
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let headers = match message.headers() {
+            None => Vec::new(),
+            Some(headers) => headers.iter().map(|it| (it.key.to_owned(), it.value.map(text).unwrap_or_default())).collect(),
+        };
         LogMessage {
-            topic: message.topic().to_string(),
-            body: payload.to_string(),
-            partition: message.partition(),
-            offset: message.offset(),
-            log: None,
-            map: None,
+            key: message.key().map(text),
+            timestamp: message.timestamp().to_millis(),
+            headers,
+            ..LogMessage::new(message.topic(), message.partition(), message.offset(), payload)
         }
     }
 }
 
-pub struct Kafka {
-    size: usize,
-    timeout: u64,
-    consumer: StreamConsumer,
+/// Timeout of the lookups of the start offsets, made while the group waits for the assignment.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a replayed batch waits for its messages, from the first one, before it is emitted
+/// without the missing ones.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay before receiving again after the first of consecutive consumption errors, doubled up to
+/// `MAX_RECV_BACKOFF` while they last.
+const RECV_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long closing waits for the partitions to be revoked before leaving the group anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn recv_backoff(failures: u32) -> Duration {
+    RECV_BACKOFF.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_RECV_BACKOFF)
 }
 
-impl Kafka {
-    pub fn create(conf: &HashMap<String, CObject>) -> Result<Kafka, SyncError> {
-        info!("Welcome to Kafka Synchronization ...");
-        let conf: Option<HashMap<String, CObject>> = conf.get("receive").ok_or(SyncError::Option)?.into();
-        let conf: Option<HashMap<String, CObject>> = conf.ok_or(SyncError::Option)?.get("kafka").ok_or(SyncError::Option)?.into();
-        let conf = conf.ok_or(SyncError::Option)?;
-
-        let server: String = conf.get("server")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.server' could not be found."))?.into();
-        let topic: String = conf.get("topic")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.topic' could not be found."))?.into();
-        let group_id: String = conf.get("group_id")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.group_id' could not be found."))?.into();
-        let username: String = conf.get("username")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.username' could not be found."))?.into();
-        let password: String = conf.get("password")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.password' could not be found."))?.into();
-        let size: f64 = conf.get("size")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.size' could not be found."))?.into();
-        let timeout: f64 = conf.get("timeout")
-            .ok_or(SyncError::MissingParams("Environment variable 'receive.kafka.timeout' could not be found."))?.into();
-
-        info!("[Kafka] Server: {}, Topic: {}, GroupId: {}", server, topic, group_id);
+type Messages = Pin<Box<dyn Stream<Item=Result<LogMessage, SyncError>> + Send>>;
 
-        // Create kafka Config.
-        let mut consumer_config = ClientConfig::new();
-        consumer_config
-            .set("group.id", &group_id)
-            .set("bootstrap.servers", &server)
-            .set("auto.offset.reset", "earliest")
-            .set("enable.auto.commit", "false")
-            .set("sasl.mechanisms", "PLAIN")
-            .set("security.protocol", "SASL_PLAINTEXT")
-            .set("sasl.username", username)
-            .set("sasl.password", password);
+/// Groups a message stream into batches of at most `size` messages, emitted when full or
+/// `timeout` milliseconds after their first message arrived.
+///
+/// Messages of an incomplete batch stay buffered in the batcher, so dropping a pending
+/// [`Batcher::next`] (e.g. in a `select!`) never loses them. Once stopped, the buffered
+/// messages are emitted right away and an empty batch follows.
+///
+/// While `replay` holds partitions, the next batch is made of their messages up to these
+/// offsets whatever its size, so that a batch the sink was writing when it stopped is cut the
+/// same way again. The other messages wait for the batches after it.
+pub struct Batcher {
+    pending: Mutex<Pending>,
+    size: usize,
+    timeout: Duration,
+    replay: Arc<std::sync::Mutex<Offsets>>,
+    stop: watch::Sender<bool>,
+}
+
+struct Pending {
+    messages: Messages,
+    batch: Vec<LogMessage>,
+    /// Received while a batch was replayed, emitted before the stream.
+    held: VecDeque<LogMessage>,
+    /// When the first message of the batch arrived.
+    started: Option<Instant>,
+    /// Whether the batch is a replayed one.
+    replayed: bool,
+}
 
-        // Kafka Consumer.
-        let consumer: StreamConsumer = consumer_config.create()?;
+impl Pending {
+    fn take(&mut self) -> Vec<LogMessage> {
+        self.started = None;
+        self.replayed = false;
+        std::mem::take(&mut self.batch)
+    }
+}
 
-        // Kafka Subscribe.
-        consumer.subscribe(&[&topic.to_owned()])?;
+impl Batcher {
+    pub fn new<S>(messages: S, size: usize, timeout: u64, replay: Arc<std::sync::Mutex<Offsets>>) -> Batcher
+    where
+        S: Stream<Item=Result<LogMessage, SyncError>> + Send + 'static,
+    {
+        let (stop, mut stopped) = watch::channel(false);
+        let messages = futures::StreamExt::take_until(messages, async move {
+            let _ = stopped.wait_for(|stop| *stop).await;
+        });
+        let pending = Pending { messages: Box::pin(messages), batch: Vec::new(), held: VecDeque::new(), started: None, replayed: false };
+        Batcher { pending: Mutex::new(pending), size, timeout: Duration::from_millis(timeout), replay, stop }
+    }
 
-        Ok(Kafka { size: size as usize, timeout: timeout as u64, consumer })
+    fn replay(&self) -> std::sync::MutexGuard<'_, Offsets> {
+        self.replay.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    pub async fn kafka_recv(&self, message_vec: &mut Vec<LogMessage>) -> Result<(), SyncError> {
+    pub async fn next(&self) -> Result<Vec<LogMessage>, SyncError> {
+        let mut pending = self.pending.lock().await;
         loop {
-            let message = self.consumer.recv().await?;
-            debug!("[Kafka] Recv offset: {:?}", message.offset());
-            self.consumer.store_offset_from_message(&message)?;
-            message_vec.push(message.into());
-            if message_vec.len() >= self.size {
-                return Ok(());
+            let replaying = !self.replay().is_empty();
+            if !replaying && pending.batch.len() >= self.size {
+                return Ok(pending.take());
+            }
+            // Partitions to replay were assigned while a batch was being filled.
+            if replaying && !pending.replayed && !pending.batch.is_empty() {
+                return Ok(pending.take());
+            }
+            let held = match replaying {
+                true => None,
+                false => pending.held.pop_front(),
+            };
+            let message = match (held, pending.started) {
+                (Some(message), _) => Some(Ok(message)),
+                (None, None) => pending.messages.next().await,
+                (None, Some(started)) => {
+                    let limit = if replaying { REPLAY_TIMEOUT } else { self.timeout };
+                    match timeout_at(started + limit, pending.messages.next()).await {
+                        Ok(message) => message,
+                        Err(_) if replaying => {
+                            warn!("[Kafka] Messages of the replayed batch are missing, it may be written twice: {:?}", self.replay());
+                            *self.replay() = Offsets::default();
+                            return Ok(pending.take());
+                        }
+                        Err(_) => return Ok(pending.take()),
+                    }
+                }
+            };
+            let message = match message {
+                // Stopped, the replayed batch can no longer be completed.
+                None if !pending.batch.is_empty() => return Ok(pending.take()),
+                None => {
+                    *self.replay() = Offsets::default();
+                    return Ok(pending.held.drain(..).collect());
+                }
+                Some(message) => message?,
+            };
+            if replaying && !replayed(&mut self.replay(), &message) {
+                pending.held.push_back(message);
+                continue;
+            }
+            pending.started.get_or_insert_with(Instant::now);
+            pending.replayed = replaying;
+            pending.batch.push(message);
+            if replaying && self.replay().is_empty() {
+                return Ok(pending.take());
             }
         }
     }
+
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
 }
 
-#[async_trait(? Send)]
-impl ReceiveTrait for Kafka {
-    async fn pull(&self) -> Result<Vec<LogMessage>, SyncError> {
-        loop {
-            let mut vec = Vec::new();
-            tokio::select! {
-                _ = self.kafka_recv(&mut vec) => {
-                    debug!("[kafka] Number of triggers. ");
+/// Whether a message belongs to the replayed batch, whose partitions are dropped from `replay`
+/// once their last offset is reached.
+fn replayed(replay: &mut Offsets, message: &LogMessage) -> bool {
+    let Some(last) = replay.get(&message.topic, message.partition) else {
+        return false;
+    };
+    if message.offset >= last {
+        replay.remove(&message.topic, message.partition);
+    }
+    message.offset <= last
+}
+
+/// Starts newly assigned partitions right after the offsets recorded by the sink, when it keeps
+/// any, instead of the offsets committed to the consumer group. Partitions without either start
+/// from `start-from`. The batch the sink was writing when it stopped is cut the same way again.
+pub struct KafkaContext {
+    store: Option<Arc<dyn OffsetStore>>,
+    /// Partitions of the batch to replay first, shared with the [`Batcher`].
+    replay: Arc<std::sync::Mutex<Offsets>>,
+    start_from: StartFrom,
+    /// The consumer of the context, set once created, to look up the committed offsets.
+    consumer: OnceLock<Weak<StreamConsumer<KafkaContext>>>,
+    /// Label of the lag metrics, the statistics are served outside of the pipeline task.
+    pipeline: String,
+    health: Arc<Health>,
+}
+
+impl KafkaContext {
+    /// The stored offsets and the intents of the sink.
+    fn load(store: &Arc<dyn OffsetStore>) -> Result<(Offsets, Offsets), SyncError> {
+        // Rebalance callbacks are synchronous and served while the consumer is polled from a
+        // runtime worker, so the lookup blocks that worker until the store answers.
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| handle.block_on(async { Ok((store.load().await?, store.intents().await?)) }))
+            }
+            _ => Err(SyncError::MissingParams("Loading stored offsets requires a multi-thread runtime.")),
+        }
+    }
+
+    /// Runs the blocking lookup `f` from a rebalance callback, served from a runtime worker like
+    /// the stored offsets, whose other tasks are moved elsewhere meanwhile.
+    fn blocking<T>(f: impl FnOnce() -> T) -> T {
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+            _ => f(),
+        }
+    }
+
+    /// Offsets `start-from` seeks the partitions of `list` the group has not committed an
+    /// offset of to, nothing when `auto.offset.reset` is enough.
+    fn start(&self, list: &TopicPartitionList) -> Result<TopicPartitionList, SyncError> {
+        let mut seek = TopicPartitionList::new();
+        if matches!(self.start_from, StartFrom::Earliest | StartFrom::Latest) || list.count() == 0 {
+            return Ok(seek);
+        }
+        let consumer = self.consumer.get().and_then(|it| it.upgrade())
+            .ok_or(SyncError::MissingParams("The consumer is not created yet."))?;
+        let committed = KafkaContext::blocking(|| consumer.committed_offsets(list.clone(), START_TIMEOUT))?;
+        let new = committed.elements().into_iter()
+            .filter(|it| it.offset() == Offset::Invalid)
+            .map(|it| (it.topic().to_owned(), it.partition()));
+        match &self.start_from {
+            StartFrom::Timestamp(timestamp) => {
+                for (topic, partition) in new {
+                    seek.add_partition_offset(&topic, partition, Offset::Offset(*timestamp))?;
+                }
+                if seek.count() > 0 {
+                    // Partitions without a message at or after the time are at their end.
+                    seek = KafkaContext::blocking(|| consumer.offsets_for_times(seek, START_TIMEOUT))?;
+                }
+            }
+            StartFrom::Offsets(offsets) => {
+                for (topic, partition) in new {
+                    let offset = offsets.iter().find(|(t, p, _)| *t == topic && *p == partition);
+                    if let Some((_, _, offset)) = offset {
+                        seek.add_partition_offset(&topic, partition, Offset::Offset(*offset))?;
+                    }
                 }
-                _ = time::sleep(Duration::from_millis(self.timeout)) => {
-                    debug!("[kafka] Time of triggers. ");
+            }
+            StartFrom::Earliest | StartFrom::Latest => {}
+        }
+        Ok(seek)
+    }
+}
+
+impl ClientContext for KafkaContext {
+    fn stats(&self, statistics: Statistics) {
+        for (topic, stats) in &statistics.topics {
+            // Partition -1 holds the messages not assigned to a partition yet.
+            for (partition, stats) in stats.partitions.iter().filter(|(partition, _)| **partition >= 0) {
+                // -1 until the lag is known.
+                if stats.consumer_lag >= 0 {
+                    METRICS.consumer_lag.with_label_values(&[&self.pipeline, topic, &partition.to_string()]).set(stats.consumer_lag);
                 }
             }
-            if vec.len() > 0 {
-                return Ok(vec);
+        }
+    }
+}
+
+impl ConsumerContext for KafkaContext {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        let list = match rebalance {
+            Rebalance::Assign(list) => list,
+            Rebalance::Revoke(list) => {
+                let mut replay = self.replay.lock().unwrap_or_else(|e| e.into_inner());
+                for elem in list.elements() {
+                    replay.remove(elem.topic(), elem.partition());
+                }
+                return;
+            }
+            Rebalance::Error(_) => return,
+        };
+        let (offsets, intents) = match self.store.as_ref().map(KafkaContext::load) {
+            None => Default::default(),
+            Some(Ok(loaded)) => loaded,
+            Some(Err(e)) => {
+                error!("[Kafka] Stored offsets could not be loaded, using the committed ones: {}", e);
+                Default::default()
+            }
+        };
+        let mut rest = TopicPartitionList::new();
+        for mut elem in list.elements() {
+            let stored = offsets.get(elem.topic(), elem.partition());
+            if let Some(last) = intents.get(elem.topic(), elem.partition()).filter(|last| stored.is_none_or(|it| it < *last)) {
+                info!("[Kafka] Partition {}-{} replays the batch written up to offset {}", elem.topic(), elem.partition(), last);
+                self.replay.lock().unwrap_or_else(|e| e.into_inner()).insert(elem.topic(), elem.partition(), last);
+            }
+            match stored {
+                Some(offset) => {
+                    info!("[Kafka] Partition {}-{} resumes after stored offset {}", elem.topic(), elem.partition(), offset);
+                    if let Err(e) = elem.set_offset(Offset::Offset(offset + 1)) {
+                        error!("[Kafka] {}", e);
+                    }
+                }
+                None => {
+                    rest.add_partition(elem.topic(), elem.partition());
+                }
+            }
+        }
+        let seek = match self.start(&rest) {
+            Ok(seek) => seek,
+            Err(e) => {
+                error!("[Kafka] Start offsets could not be looked up, new partitions start from the {}: {}", self.start_from.reset(), e);
+                return;
+            }
+        };
+        for elem in seek.elements() {
+            let Some(mut assigned) = list.find_partition(elem.topic(), elem.partition()) else {
+                continue;
+            };
+            info!("[Kafka] Partition {}-{} starts from offset {:?}", elem.topic(), elem.partition(), elem.offset());
+            if let Err(e) = assigned.set_offset(elem.offset()) {
+                error!("[Kafka] {}", e);
             }
         }
     }
 
-    async fn confirm(&self) -> Result<(), SyncError> {
-        self.consumer.commit_consumer_state(CommitMode::Sync)?;
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        let assignment = self.consumer.get().and_then(|it| it.upgrade()).map(|it| it.assignment());
+        if let Some(assigned) = assigned(rebalance, assignment) {
+            self.health.assigned(assigned);
+        }
+    }
+}
+
+/// Whether partitions remain assigned after a rebalance, from the `assignment` of the consumer
+/// once applied. With cooperative-sticky, the lists of the rebalance only hold the partitions
+/// added or taken away, the consumer keeps the others.
+fn assigned(rebalance: &Rebalance, assignment: Option<KafkaResult<TopicPartitionList>>) -> Option<bool> {
+    match (rebalance, assignment) {
+        (Rebalance::Error(_), _) => None,
+        (_, Some(Ok(list))) => Some(list.count() > 0),
+        (_, Some(Err(e))) => {
+            error!("[Kafka] Assignment could not be read: {}", e);
+            None
+        }
+        // Not created yet, the lists of an eager rebalance are the whole assignment.
+        (Rebalance::Assign(list), None) => Some(list.count() > 0),
+        (Rebalance::Revoke(_), None) => Some(false),
+    }
+}
+
+pub struct Kafka {
+    batcher: Batcher,
+    consumer: Arc<StreamConsumer<KafkaContext>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Kafka {
+    /// Connection settings shared by every client of the cluster, followed by the `defaults` of
+    /// the client and the `properties` of the configuration, which prevail.
+    pub fn client_config(conf: &KafkaConfig, defaults: &[(&str, &str)]) -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        let protocol = conf.protocol();
+        client_config
+            .set("bootstrap.servers", &conf.server)
+            .set("security.protocol", protocol.as_str());
+        if protocol.is_sasl() {
+            client_config.set("sasl.mechanisms", conf.sasl_mechanism.as_str());
+        }
+        let mut optional = vec![("sasl.username", &conf.username), ("sasl.password", &conf.password)];
+        if let Some(ssl) = &conf.ssl {
+            optional.extend([
+                ("ssl.ca.location", &ssl.ca_location),
+                ("ssl.certificate.location", &ssl.certificate_location),
+                ("ssl.key.location", &ssl.key_location),
+                ("ssl.key.password", &ssl.key_password),
+            ]);
+        }
+        for (key, value) in optional {
+            if let Some(value) = value {
+                client_config.set(key, value);
+            }
+        }
+        for (key, value) in defaults {
+            client_config.set(*key, *value);
+        }
+        for (key, value) in &conf.properties {
+            client_config.set(key, value);
+        }
+        client_config
+    }
+
+    pub fn create(conf: &KafkaConfig, store: Option<Arc<dyn OffsetStore>>) -> Result<Kafka, SyncError> {
+        info!("Welcome to Kafka Synchronization ...");
+        let KafkaConfig { server, topics, group_id, start_from, size, timeout, .. } = conf;
+
+        info!("[Kafka] Server: {}, Topics: {}, GroupId: {}, Protocol: {}, Start: {:?}", server, topics.join(", "), group_id, conf.protocol().as_str(), start_from);
+
+        // Create kafka Config.
+        let consumer_config = Kafka::client_config(conf, &[
+            ("group.id", group_id),
+            ("auto.offset.reset", start_from.reset()),
+            ("enable.auto.commit", "false"),
+            ("enable.auto.offset.store", "false"),
+            // Source of the consumer lag metric.
+            ("statistics.interval.ms", "15000"),
+        ]);
+
+        // Kafka Consumer, without partitions until the group assigns some.
+        let health = health::current();
+        health.assigned(false);
+        let replay = Arc::new(std::sync::Mutex::new(Offsets::default()));
+        let context = KafkaContext {
+            store,
+            replay: replay.clone(),
+            start_from: start_from.clone(),
+            consumer: OnceLock::new(),
+            pipeline: pipeline(),
+            health,
+        };
+        let consumer: Arc<StreamConsumer<KafkaContext>> = Arc::new(consumer_config.create_with_context(context)?);
+        let _ = consumer.context().consumer.set(Arc::downgrade(&consumer));
+
+        // Kafka Subscribe, librdkafka matches the topics starting with `^` as patterns.
+        let topics: Vec<&str> = topics.iter().map(|it| it.as_str()).collect();
+        consumer.subscribe(&topics)?;
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let messages = stream::unfold((consumer.clone(), paused.clone()), |(consumer, paused)| async move {
+            let mut failures = 0;
+            loop {
+                match consumer.recv().await {
+                    Ok(message) => {
+                        debug!("[Kafka] Recv offset: {:?}", message.offset());
+                        // Partitions assigned by a rebalance while paused are not paused yet.
+                        if paused.load(Ordering::SeqCst) {
+                            if let Err(e) = consumer.assignment().and_then(|it| consumer.pause(&it)) {
+                                warn!("[Kafka] {}", e);
+                            }
+                        }
+                        return Some((Ok(message.into()), (consumer, paused)));
+                    }
+                    // Fatal errors (e.g. a fenced consumer) leave the consumer unusable.
+                    Err(e) if consumer.client().fatal_error().is_some() => {
+                        return Some((Err(e.into()), (consumer, paused)));
+                    }
+                    // librdkafka recovers from the other consumption errors (e.g. a broker going
+                    // away) by itself, until then they are raised on each receive.
+                    Err(e) => {
+                        failures += 1;
+                        let delay = recv_backoff(failures);
+                        warn!("[Kafka] {}, receiving again in {:?}", e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+        });
+
+        Ok(Kafka {
+            batcher: Batcher::new(messages, *size, *timeout, replay),
+            consumer,
+            paused,
+        })
+    }
+}
+
+#[async_trait]
+impl ReceiveTrait for Kafka {
+    async fn pull(&self) -> Result<Vec<LogMessage>, SyncError> {
+        let batch = self.batcher.next().await?;
+        debug!("[Kafka] Pulled a batch of {} messages", batch.len());
+        if !batch.is_empty() {
+            let pipeline = pipeline();
+            METRICS.batch_size.with_label_values(&[&pipeline]).observe(batch.len() as f64);
+            let mut consumed: HashMap<(&str, i32), u64> = HashMap::new();
+            for x in &batch {
+                *consumed.entry((&x.topic, x.partition)).or_default() += 1;
+            }
+            for ((topic, partition), count) in consumed {
+                METRICS.consumed.with_label_values(&[&pipeline, topic, &partition.to_string()]).inc_by(count);
+            }
+        }
+        Ok(batch)
+    }
+
+    async fn confirm(&self, offsets: &Offsets) -> Result<(), SyncError> {
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let mut list = TopicPartitionList::new();
+        for (topic, partition, offset) in offsets.iter() {
+            // The committed offset is the next message to consume.
+            list.add_partition_offset(topic, partition, Offset::Offset(offset + 1))?;
+        }
+        self.consumer.commit(&list, CommitMode::Sync)?;
+        debug!("[Kafka] Committed {:?}", offsets);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.batcher.stop();
+    }
+
+    fn pause(&self) -> Result<(), SyncError> {
+        self.paused.store(true, Ordering::SeqCst);
+        self.consumer.pause(&self.consumer.assignment()?)?;
+        info!("[Kafka] Consumption paused");
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<(), SyncError> {
+        self.paused.store(false, Ordering::SeqCst);
+        self.consumer.resume(&self.consumer.assignment()?)?;
+        info!("[Kafka] Consumption resumed");
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), SyncError> {
+        self.consumer.unsubscribe();
+        // The revoke is only served while receiving, until then the group waits for the consumer
+        // and rebalances once more after its session times out. The messages still received are
+        // not committed, the next owners of their partitions get them.
+        let revoked = timeout_at(Instant::now() + CLOSE_TIMEOUT, async {
+            while self.consumer.assignment().is_ok_and(|it| it.count() > 0) {
+                let _ = timeout_at(Instant::now() + Duration::from_millis(100), self.consumer.recv()).await;
+            }
+        }).await;
+        if revoked.is_err() {
+            warn!("[Kafka] Partitions still assigned after {:?}, leaving the consumer group anyway", CLOSE_TIMEOUT);
+        }
+        info!("[Kafka] Left the consumer group");
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use rdkafka::consumer::BaseConsumer;
+    use rdkafka::error::KafkaError;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
 
+    use crate::config::Log2ClickConfig;
+
+    use super::*;
+
+    fn message(offset: i64) -> Result<LogMessage, SyncError> {
+        Ok(LogMessage::new("logs", 0, offset, ""))
+    }
+
+    fn offsets(batch: &[LogMessage]) -> Vec<i64> {
+        batch.iter().map(|it| it.offset).collect()
+    }
+
+    #[tokio::test]
+    async fn batch_by_size_or_time() -> Result<(), SyncError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let batcher = Batcher::new(UnboundedReceiverStream::new(receiver), 2, 50, Default::default());
+        for offset in 0..3 {
+            sender.send(message(offset)).unwrap();
+        }
+        assert_eq!(offsets(&batcher.next().await?), vec![0, 1]);
+        assert_eq!(offsets(&batcher.next().await?), vec![2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_pull_keeps_received_messages() -> Result<(), SyncError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let batcher = Batcher::new(UnboundedReceiverStream::new(receiver), 3, 200, Default::default());
+        sender.send(message(0)).unwrap();
+        // The pull is cancelled after the first message has been received.
+        assert!(timeout(Duration::from_millis(20), batcher.next()).await.is_err());
+        sleep(Duration::from_millis(20)).await;
+        sender.send(message(1)).unwrap();
+        sender.send(message(2)).unwrap();
+        assert_eq!(offsets(&batcher.next().await?), vec![0, 1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_flushes_the_incomplete_batch() -> Result<(), SyncError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let batcher = Batcher::new(UnboundedReceiverStream::new(receiver), 3, 60_000, Default::default());
+        sender.send(message(0)).unwrap();
+        assert!(timeout(Duration::from_millis(20), batcher.next()).await.is_err());
+        batcher.stop();
+        assert_eq!(offsets(&batcher.next().await?), vec![0]);
+        assert!(batcher.next().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_batch_is_cut_again() -> Result<(), SyncError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut replay = Offsets::default();
+        replay.insert("logs", 0, 2);
+        let batcher = Batcher::new(UnboundedReceiverStream::new(receiver), 2, 60_000, Arc::new(std::sync::Mutex::new(replay)));
+        for (partition, offset) in [(1, 7), (0, 1), (0, 2), (1, 8), (0, 3)] {
+            sender.send(Ok(LogMessage::new("logs", partition, offset, ""))).unwrap();
+        }
+        // Larger than the batch size, without the messages of the other partitions.
+        assert_eq!(offsets(&batcher.next().await?), vec![1, 2]);
+        assert_eq!(offsets(&batcher.next().await?), vec![7, 8]);
+        drop(sender);
+        assert_eq!(offsets(&batcher.next().await?), vec![3]);
+        Ok(())
+    }
+
+    /// Sink that wrote the rows of the batch ending at `intents` but not its offsets.
+    struct Stored {
+        offsets: Offsets,
+        intents: Offsets,
+    }
+
+    #[async_trait]
+    impl OffsetStore for Stored {
+        async fn load(&self) -> Result<Offsets, SyncError> {
+            Ok(self.offsets.clone())
+        }
+
+        async fn intents(&self) -> Result<Offsets, SyncError> {
+            Ok(self.intents.clone())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn assigned_partitions_resume_after_the_stored_offsets() {
+        let mut store = Stored { offsets: Offsets::default(), intents: Offsets::default() };
+        store.offsets.insert("logs", 0, 41);
+        store.offsets.insert("logs", 1, 9);
+        store.intents.insert("logs", 0, 45);
+        store.intents.insert("logs", 1, 9);
+        let context = KafkaContext {
+            store: Some(Arc::new(store)),
+            replay: Default::default(),
+            start_from: StartFrom::Latest,
+            consumer: OnceLock::new(),
+            pipeline: String::from("test"),
+            health: Arc::new(Health::default()),
+        };
+        let mut list = TopicPartitionList::new();
+        for partition in 0..3 {
+            list.add_partition("logs", partition);
+        }
+        context.pre_rebalance(&Rebalance::Assign(&list));
+        // Partition 2 has no stored offset, it starts from the committed one.
+        let start: Vec<Offset> = list.elements().iter().map(|it| it.offset()).collect();
+        assert_eq!(start, vec![Offset::Offset(42), Offset::Offset(10), Offset::Invalid]);
+        // Only partition 0 stopped between its rows and its offsets.
+        let replay = context.replay.lock().unwrap().clone();
+        assert_eq!(replay.iter().collect::<Vec<_>>(), vec![("logs", 0, 45)]);
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("logs", 0);
+        context.pre_rebalance(&Rebalance::Revoke(&revoked));
+        assert!(context.replay.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn consumption_errors_back_off() {
+        let delays: Vec<Duration> = [1, 2, 3, 8, 100].into_iter().map(recv_backoff).collect();
+        assert_eq!(delays, [100, 200, 400, 10_000, 10_000].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn incremental_revoke_keeps_partitions_assigned() {
+        let list = |partitions: &[i32]| {
+            let mut list = TopicPartitionList::new();
+            for partition in partitions {
+                list.add_partition("logs", *partition);
+            }
+            list
+        };
+        let revoked = list(&[1]);
+        // Partition 0 stays with the consumer.
+        assert_eq!(assigned(&Rebalance::Revoke(&revoked), Some(Ok(list(&[0])))), Some(true));
+        assert_eq!(assigned(&Rebalance::Revoke(&revoked), Some(Ok(list(&[])))), Some(false));
+        assert_eq!(assigned(&Rebalance::Assign(&list(&[])), Some(Ok(list(&[0, 1])))), Some(true));
+        assert_eq!(assigned(&Rebalance::Revoke(&revoked), None), Some(false));
+        assert_eq!(assigned(&Rebalance::Error(KafkaError::Canceled), Some(Ok(list(&[])))), None);
+    }
+
+    fn sasl_ssl_config() -> Result<ClientConfig, SyncError> {
+        let conf = Log2ClickConfig::from_yaml(r#"
+receive:
+  kafka:
+    server: 127.0.0.1:9093
+    topic: logs
+    group-id: log2click
+    security-protocol: SASL_SSL
+    sasl-mechanism: SCRAM-SHA-512
+    username: admin
+    password: admin
+parser:
+  regex: '(.*)'
+  mapping: message
+sender:
+  mapping:
+    message: message
+  clickhouse:
+    server: http://127.0.0.1:8123
+    database: logs
+    table: app
+"#)?;
+        Ok(Kafka::client_config(&conf.pipelines[0].receive.kafka, &[("group.id", "log2click")]))
+    }
+
+    #[test]
+    fn sasl_ssl_client() -> Result<(), SyncError> {
+        let client_config = sasl_ssl_config()?;
+        assert_eq!(client_config.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(client_config.get("sasl.mechanisms"), Some("SCRAM-SHA-512"));
+        assert_eq!(client_config.get("sasl.username"), Some("admin"));
+        Ok(())
+    }
+
+    #[test]
+    fn sasl_ssl_client_is_created() -> Result<(), SyncError> {
+        // librdkafka built without OpenSSL rejects both the protocol and the mechanism.
+        let _: BaseConsumer = sasl_ssl_config()?.create()?;
+        Ok(())
+    }
+}